@@ -1,4 +1,3 @@
-/// Connection mode, indicate is keep alive or not.
 // Copyright 2022 jmjoy
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -13,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// Connection mode, indicate is keep alive or not.
 pub trait Mode {
     fn is_keep_alive() -> bool;
 }
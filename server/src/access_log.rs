@@ -0,0 +1,540 @@
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use http::{
+    header::{REFERER, USER_AGENT},
+    HeaderValue, Method, Request, StatusCode, Uri, Version,
+};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    auth::User,
+    request_id::RequestId,
+    service::{self, Route},
+};
+
+const BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Common,
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Write an access log to `stdout` or a file, reopened on SIGHUP
+    pub output: Option<String>,
+
+    pub format: Format,
+
+    /// Fraction of requests to log, server errors are always logged
+    pub sample: f64,
+
+    /// Request paths excluded from the access log, a trailing `*` matches a prefix
+    pub exclude: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            output: None,
+            format: Format::Combined,
+            sample: 1.0,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Time spent on the FastCGI side of a request, set as a response extension.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTime(pub Duration);
+
+/// Request details captured before the request is handed to the service.
+pub struct Record {
+    time: DateTime<Utc>,
+    start: Instant,
+    client: Option<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    referer: Option<HeaderValue>,
+    user_agent: Option<HeaderValue>,
+    request_id: Option<String>,
+}
+
+impl Record {
+    pub fn new<B>(request: &Request<B>, client: Option<SocketAddr>) -> Self {
+        let header = |name| request.headers().get(name).cloned();
+
+        Self {
+            time: Utc::now(),
+            start: Instant::now(),
+            client,
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            request_id: request
+                .extensions()
+                .get::<RequestId>()
+                .map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: String,
+    client_ip: Option<String>,
+    method: &'a str,
+    path: &'a str,
+    query: Option<&'a str>,
+    protocol: String,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    upstream_ms: Option<f64>,
+    user: Option<&'a str>,
+    referer: Option<Cow<'a, str>>,
+    user_agent: Option<Cow<'a, str>>,
+    request_id: Option<&'a str>,
+    route: &'static str,
+}
+
+/// Escapes a value for a quoted CLF field the way Apache does, with `\"`, `\\` and
+/// `\xHH` for control and non-ASCII bytes.
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for &byte in value {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+
+    escaped
+}
+
+fn quoted(value: Option<&HeaderValue>) -> String {
+    value
+        .map(|value| escape(value.as_bytes()))
+        .unwrap_or_else(|| "-".into())
+}
+
+struct Completed {
+    record: Record,
+    status: StatusCode,
+    route: Route,
+    upstream: Option<Duration>,
+    user: Option<String>,
+}
+
+impl Completed {
+    fn format(&self, format: Format, bytes: u64) -> String {
+        let record = &self.record;
+        let client = record
+            .client
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "-".into());
+
+        match format {
+            Format::Common | Format::Combined => {
+                let user = match &self.user {
+                    Some(user) if !user.is_empty() => escape(user.as_bytes()),
+                    _ => "-".into(),
+                };
+                let mut line = format!(
+                    "{client} - {user} [{}] \"{} {} {:?}\" {} {}",
+                    record.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                    record.method,
+                    record.uri,
+                    record.version,
+                    self.status.as_u16(),
+                    if bytes == 0 {
+                        "-".into()
+                    } else {
+                        bytes.to_string()
+                    },
+                );
+
+                if let Format::Combined = format {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        quoted(record.referer.as_ref()),
+                        quoted(record.user_agent.as_ref()),
+                    ));
+                }
+
+                line
+            }
+            Format::Json => serde_json::to_string(&JsonRecord {
+                time: record.time.to_rfc3339(),
+                client_ip: record.client.map(|addr| addr.ip().to_string()),
+                method: record.method.as_str(),
+                path: record.uri.path(),
+                query: record.uri.query(),
+                protocol: format!("{:?}", record.version),
+                status: self.status.as_u16(),
+                bytes,
+                duration_ms: record.start.elapsed().as_secs_f64() * 1000.0,
+                upstream_ms: self.upstream.map(|d| d.as_secs_f64() * 1000.0),
+                user: self.user.as_deref(),
+                referer: record
+                    .referer
+                    .as_ref()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes())),
+                user_agent: record
+                    .user_agent
+                    .as_ref()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes())),
+                request_id: record.request_id.as_deref(),
+                route: self.route.as_str(),
+            })
+            .unwrap_or_default(),
+        }
+    }
+}
+
+enum Message {
+    Line(String),
+    /// Flushes the buffered lines, then answers
+    Flush(oneshot::Sender<()>),
+}
+
+pub struct AccessLog {
+    options: Options,
+    sender: mpsc::Sender<Message>,
+    seen: AtomicU64,
+}
+
+impl AccessLog {
+    /// Starts the writer task, returns `None` when the access log is disabled.
+    pub async fn start(options: Options) -> std::io::Result<Option<Self>> {
+        let Some(output) = options.output.clone() else {
+            return Ok(None);
+        };
+
+        let (sender, receiver) = mpsc::channel(BUFFER);
+        let path = (output != "stdout").then(|| PathBuf::from(output));
+        let writer = open(path.as_ref()).await?;
+
+        tokio::spawn(write(path, writer, receiver));
+
+        Ok(Some(Self {
+            options,
+            sender,
+            seen: AtomicU64::new(0),
+        }))
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.options
+            .exclude
+            .iter()
+            .any(|exclude| match exclude.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == exclude,
+            })
+    }
+
+    /// Spreads sampled records evenly instead of relying on randomness.
+    fn is_sampled(&self) -> bool {
+        let rate = self.options.sample.clamp(0.0, 1.0);
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+
+        (n * rate).floor() != ((n + 1.0) * rate).floor()
+    }
+
+    /// Wraps the response body so the record is written once the body is done.
+    pub fn wrap(
+        self: &Arc<Self>,
+        record: Record,
+        response: Response<BoxBody<Bytes, service::Error>>,
+    ) -> Response<BoxBody<Bytes, service::Error>> {
+        let status = response.status();
+
+        if self.is_excluded(record.uri.path()) || (!status.is_server_error() && !self.is_sampled())
+        {
+            return response;
+        }
+
+        let completed = Completed {
+            record,
+            status,
+            route: response
+                .extensions()
+                .get::<Route>()
+                .copied()
+                .unwrap_or(Route::Php),
+            upstream: response.extensions().get::<UpstreamTime>().map(|t| t.0),
+            user: response.extensions().get::<User>().map(|u| u.name.clone()),
+        };
+        let log = Arc::clone(self);
+
+        response.map(|inner| {
+            Logged {
+                inner,
+                bytes: 0,
+                completed: Some(completed),
+                log,
+            }
+            .boxed()
+        })
+    }
+
+    fn emit(&self, completed: Completed, bytes: u64) {
+        let line = completed.format(self.options.format, bytes);
+
+        if self.sender.try_send(Message::Line(line)).is_err() {
+            tracing::warn!("access log buffer full, dropping record");
+        }
+    }
+
+    /// Waits until the records sent so far are written out, called on shutdown.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+type Writer = BufWriter<Pin<Box<dyn AsyncWrite + Send>>>;
+
+async fn open(path: Option<&PathBuf>) -> std::io::Result<Writer> {
+    let writer: Pin<Box<dyn AsyncWrite + Send>> = match path {
+        Some(path) => Box::pin(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?,
+        ),
+        None => Box::pin(tokio::io::stdout()),
+    };
+
+    Ok(BufWriter::new(writer))
+}
+
+async fn write(path: Option<PathBuf>, mut writer: Writer, mut receiver: mpsc::Receiver<Message>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install signal handler");
+
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(Message::Line(mut line)) => {
+                    line.push('\n');
+
+                    if let Err(e) = writer.write_all(line.as_bytes()).await {
+                        tracing::error!({ error = ?e }, "failed to write access log");
+                    }
+
+                    // Lines are batched under load and written out once the queue drains
+                    if receiver.is_empty() {
+                        let _ = writer.flush().await;
+                    }
+                }
+                Some(Message::Flush(done)) => {
+                    let _ = writer.flush().await;
+                    let _ = done.send(());
+                }
+                None => {
+                    let _ = writer.flush().await;
+                    break;
+                }
+            },
+            _ = hangup.recv() => {
+                if path.is_none() {
+                    continue;
+                }
+
+                let _ = writer.flush().await;
+
+                match open(path.as_ref()).await {
+                    Ok(reopened) => {
+                        tracing::info!({ ?path }, "reopened access log");
+                        writer = reopened;
+                    }
+                    Err(e) => tracing::error!({ error = ?e }, "failed to reopen access log"),
+                }
+            }
+        }
+    }
+}
+
+/// Body wrapper that counts bytes and emits the access log record when dropped.
+struct Logged {
+    inner: BoxBody<Bytes, service::Error>,
+    bytes: u64,
+    completed: Option<Completed>,
+    log: Arc<AccessLog>,
+}
+
+impl Body for Logged {
+    type Data = Bytes;
+    type Error = service::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        if let Some(completed) = self.completed.take() {
+            self.log.emit(completed, self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completed(request: Request<()>, user: Option<&str>) -> Completed {
+        Completed {
+            record: Record::new(&request, Some(([192, 0, 2, 1], 4000).into())),
+            status: StatusCode::OK,
+            route: Route::Php,
+            upstream: None,
+            user: user.map(String::from),
+        }
+    }
+
+    #[test]
+    fn escapes_like_apache() {
+        assert_eq!(escape(b"curl/8.0"), "curl/8.0");
+        assert_eq!(escape(br#"a "quoted" \ value"#), r#"a \"quoted\" \\ value"#);
+        assert_eq!(escape(b"tab\there"), r"tab\x09here");
+        assert_eq!(escape("caf\u{e9}".as_bytes()), r"caf\xc3\xa9");
+    }
+
+    #[test]
+    fn combined_escapes_quoted_fields() {
+        let request = Request::get("/index.php?a=1")
+            .header(REFERER, "https://example.com/")
+            .header(
+                USER_AGENT,
+                HeaderValue::from_bytes(b"evil\" \"agent\xff").unwrap(),
+            )
+            .body(())
+            .unwrap();
+        let line = completed(request, None).format(Format::Combined, 12);
+
+        assert!(line.starts_with("192.0.2.1 - - ["), "{line}");
+        assert!(
+            line.ends_with(r#""GET /index.php?a=1 HTTP/1.1" 200 12 "https://example.com/" "evil\" \"agent\xff""#),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn logs_authenticated_user() {
+        let request = Request::get("/").body(()).unwrap();
+
+        let line = completed(request, Some("alice")).format(Format::Common, 0);
+        assert!(line.starts_with("192.0.2.1 - alice ["), "{line}");
+        assert!(line.ends_with("200 -"), "{line}");
+
+        let request = Request::get("/").body(()).unwrap();
+        let line = completed(request, Some("a b\"c")).format(Format::Json, 0);
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["user"], "a b\"c");
+        assert_eq!(json["referer"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn samples_evenly() {
+        let log = |sample| AccessLog {
+            options: Options {
+                sample,
+                ..Options::default()
+            },
+            sender: mpsc::channel(1).0,
+            seen: AtomicU64::new(0),
+        };
+
+        let half = log(0.5);
+        assert_eq!((0..10).filter(|_| half.is_sampled()).count(), 5);
+
+        let none = log(0.0);
+        assert!(!(0..10).any(|_| none.is_sampled()));
+    }
+
+    #[tokio::test]
+    async fn flush_writes_pending_lines() {
+        let path = std::env::temp_dir().join(format!("pyper-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = AccessLog::start(Options {
+            output: Some(path.display().to_string()),
+            ..Options::default()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        let request = Request::get("/flushed").body(()).unwrap();
+        log.emit(completed(request, None), 3);
+        log.flush().await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(written.lines().count(), 1);
+        assert!(
+            written.contains("\"GET /flushed HTTP/1.1\" 200 3"),
+            "{written}"
+        );
+    }
+}
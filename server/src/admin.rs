@@ -0,0 +1,421 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderValue, Method, StatusCode,
+};
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1::Builder,
+    service::service_fn,
+    Request, Response,
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    auth::token_matches, fpm_status, metrics::METRICS, service::PhpService, upstream::Backend,
+};
+
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Address serving `/metrics`, `/healthz`, `/readyz`, `/pool` and `/maintenance`,
+    /// disabled when unset
+    pub listen: Option<SocketAddr>,
+
+    /// Bearer token required by the `/pool` and `/maintenance` endpoints, which only
+    /// answer loopback clients when unset
+    pub token: Option<String>,
+}
+
+fn json(body: Vec<u8>) -> Response<Full<Bytes>> {
+    let mut response = text(StatusCode::OK, body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    /// Requests are answered with the maintenance page
+    active: bool,
+    /// Enabled through the admin API
+    enabled: bool,
+    /// The flag file exists
+    flagged: bool,
+}
+
+#[derive(Serialize)]
+struct ConnStatus {
+    id: u64,
+    age_secs: u64,
+    idle_secs: u64,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+struct BackendStatus {
+    upstream: String,
+    addr: SocketAddr,
+    max_size: u32,
+    connections: u32,
+    idle: u32,
+    in_use: u32,
+    waiting: usize,
+    outstanding: usize,
+    ejected: bool,
+    conns: Vec<ConnStatus>,
+    /// Last values read from the php-fpm status page
+    fpm: Option<fpm_status::Status>,
+}
+
+impl BackendStatus {
+    fn new(backend: &Backend) -> Self {
+        let state = backend.pool().state();
+        let conns = backend
+            .manager()
+            .connections()
+            .iter()
+            .map(|conn| ConnStatus {
+                id: conn.id(),
+                age_secs: conn.age().as_secs(),
+                idle_secs: conn.idle().as_secs(),
+                requests: conn.requests(),
+            })
+            .collect();
+
+        Self {
+            upstream: backend.upstream().to_string(),
+            addr: backend.addr(),
+            max_size: backend.max_size(),
+            connections: state.connections,
+            idle: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+            waiting: backend.waiting(),
+            outstanding: backend.outstanding(),
+            ejected: backend.is_ejected(),
+            conns,
+            fpm: backend.fpm_status().map(|status| (*status).clone()),
+        }
+    }
+}
+
+/// Value of a query parameter, `None` when missing.
+fn query<'a>(request: &'a Request<Incoming>, name: &str) -> Option<&'a str> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[derive(Clone)]
+pub struct Admin {
+    service: PhpService,
+    ready: Arc<AtomicBool>,
+    token: Option<Arc<String>>,
+}
+
+impl Admin {
+    pub fn new(service: PhpService, ready: Arc<AtomicBool>) -> Self {
+        Self {
+            service,
+            ready,
+            token: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(Arc::new(token));
+        self
+    }
+
+    fn authorized(&self, request: &Request<Incoming>, remote: SocketAddr) -> bool {
+        let Some(token) = &self.token else {
+            return remote.ip().is_loopback();
+        };
+
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|given| token_matches(token, given))
+    }
+
+    /// Backends of every upstream, or the one given by the `backend` parameter.
+    fn backends(&self, request: &Request<Incoming>) -> Result<Vec<Arc<Backend>>, String> {
+        let addr = query(request, "backend")
+            .map(|addr| addr.parse::<SocketAddr>())
+            .transpose()
+            .map_err(|e| format!("invalid backend: {e}"))?;
+
+        let backends: Vec<_> = self
+            .service
+            .upstreams()
+            .iter()
+            .flat_map(|upstream| upstream.backends())
+            .filter(|backend| addr.is_none_or(|addr| backend.addr() == addr))
+            .cloned()
+            .collect();
+
+        match backends.is_empty() {
+            true => Err("no such backend".into()),
+            false => Ok(backends),
+        }
+    }
+
+    async fn pool(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        let backends = match self.backends(request) {
+            Ok(backends) => backends,
+            Err(reason) => return text(StatusCode::NOT_FOUND, reason),
+        };
+
+        let max_size = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/pool") => {
+                let status: Vec<_> = backends.iter().map(|b| BackendStatus::new(b)).collect();
+                return json(serde_json::to_vec(&status).expect("status serializes"));
+            }
+            (&Method::POST, "/pool/recycle") => None,
+            (&Method::POST, "/pool/resize") => {
+                match query(request, "max_size").map(str::parse::<u32>) {
+                    Some(Ok(max_size)) if max_size > 0 => Some(max_size),
+                    _ => return text(StatusCode::BAD_REQUEST, "max_size must be at least 1"),
+                }
+            }
+            _ => return text(StatusCode::NOT_FOUND, "not found"),
+        };
+
+        for backend in &backends {
+            match max_size {
+                Some(max_size) => backend.resize(max_size),
+                None => backend.recycle(),
+            }
+        }
+
+        text(StatusCode::OK, "ok")
+    }
+
+    fn maintenance(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        let state = self.service.state();
+        let maintenance = state.maintenance();
+
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/maintenance") => {
+                let status = MaintenanceStatus {
+                    active: maintenance.is_active(),
+                    enabled: maintenance.is_enabled(),
+                    flagged: maintenance.is_flagged(),
+                };
+                json(serde_json::to_vec(&status).expect("status serializes"))
+            }
+            (&Method::POST, "/maintenance/enable") => {
+                maintenance.set_enabled(true);
+                text(StatusCode::OK, "ok")
+            }
+            (&Method::POST, "/maintenance/disable") => {
+                maintenance.set_enabled(false);
+                text(StatusCode::OK, "ok")
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    /// Ready when not shutting down and every upstream in use has a backend that
+    /// is not ejected and answers the ping script, if one is configured.
+    async fn readiness(&self) -> Result<(), String> {
+        if !self.ready.load(Ordering::Relaxed) {
+            return Err("shutting down".into());
+        }
+
+        let upstreams = self.service.upstreams();
+        if upstreams.is_empty() {
+            return Err("no upstreams".into());
+        }
+
+        let checks = upstreams.iter().map(|upstream| async move {
+            let check = async {
+                let backend = upstream
+                    .backends()
+                    .iter()
+                    .find(|backend| !backend.is_ejected())
+                    .ok_or_else(|| "all backends are ejected".to_string())?;
+                let mut conn = backend.get().await.map_err(|e| e.to_string())?;
+
+                // The pool skips the ping for connections validated within the interval
+                conn.ping().await.map_err(|e| e.to_string())
+            };
+
+            let name = upstream.backends()[0].upstream();
+            // Boxed, a checkout with its ping is too large a future to keep on the stack
+            tokio::time::timeout(READY_TIMEOUT, Box::pin(check))
+                .await
+                .map_err(|_| "timed out checking out a connection".to_string())
+                .flatten()
+                .map_err(|reason| format!("{name}: {reason}"))
+        });
+
+        futures::future::try_join_all(checks).await.map(drop)
+    }
+
+    async fn handle(
+        self,
+        request: Request<Incoming>,
+        remote: SocketAddr,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let response = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                let mut response = text(StatusCode::OK, METRICS.encode());
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    prometheus::TEXT_FORMAT.parse().expect("valid content type"),
+                );
+                response
+            }
+            (&Method::GET, "/healthz") => text(StatusCode::OK, "ok"),
+            (&Method::GET, "/readyz") => match self.readiness().await {
+                Ok(()) => text(StatusCode::OK, "ok"),
+                Err(reason) => {
+                    tracing::warn!({ reason }, "readiness check failed");
+                    text(StatusCode::SERVICE_UNAVAILABLE, reason)
+                }
+            },
+            (_, path) if path == "/pool" || path.starts_with("/pool/") => {
+                match self.authorized(&request, remote) {
+                    true => self.pool(&request).await,
+                    false => text(StatusCode::FORBIDDEN, "forbidden"),
+                }
+            }
+            (_, path) if path == "/maintenance" || path.starts_with("/maintenance/") => {
+                match self.authorized(&request, remote) {
+                    true => self.maintenance(&request),
+                    false => text(StatusCode::FORBIDDEN, "forbidden"),
+                }
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found"),
+        };
+
+        Ok(response)
+    }
+
+    /// Serves the admin endpoints on a listener bound at startup, separate from
+    /// the application listener.
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        tracing::info!({ addr = %listener.local_addr()? }, "admin listener started");
+
+        loop {
+            let (tcp, remote) = listener.accept().await?;
+            let io = TokioIo::new(tcp);
+            let admin = self.clone();
+            let service = service_fn(move |request| admin.clone().handle(request, remote));
+
+            tokio::task::spawn(async move {
+                if let Err(e) = Builder::new().serve_connection(io, service).await {
+                    tracing::warn!({ error = ?e }, "failed to serve admin connection");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, reload, service::tests::backend, service::PhpService};
+
+    const PONG: &[u8] = b"Content-Type: text/plain\r\n\r\npong";
+    const BUSY: &[u8] = b"Status: 503\r\n\r\nbusy";
+
+    /// Config with a ping script validating connections once a minute.
+    fn pinging(backend: SocketAddr) -> Config {
+        let mut config = Config::default();
+        config.fastcgi.bind = backend;
+        config.fastcgi.ping_path = Some("/ping".into());
+        config.fastcgi.ping_interval = 60;
+        config
+    }
+
+    async fn admin(ready: bool) -> Admin {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = Config::default();
+        config.fastcgi.bind = closed;
+
+        let service = PhpService::new(reload::state(&config, None).await.unwrap());
+        Admin::new(service, Arc::new(AtomicBool::new(ready)))
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_shutting_down() {
+        assert_eq!(
+            admin(false).await.readiness().await.unwrap_err(),
+            "shutting down"
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_backend() {
+        let admin = admin(true).await;
+        let reason = tokio::time::timeout(Duration::from_secs(1), admin.readiness())
+            .await
+            .expect("failed connects are reported right away")
+            .unwrap_err();
+
+        assert!(reason.contains("failed to connect"), "{reason}");
+    }
+
+    #[tokio::test]
+    async fn pings_on_every_readiness_check() {
+        // Validation on checkout and the first readiness ping succeed, then PHP is down
+        let config = pinging(backend(&[PONG, PONG, BUSY]).await);
+        let service = PhpService::new(reload::state(&config, None).await.unwrap());
+        let admin = Admin::new(service, Arc::new(AtomicBool::new(true)));
+
+        admin.readiness().await.unwrap();
+        let reason = admin.readiness().await.unwrap_err();
+
+        assert_eq!(reason, "default: ping failed: status 503, expected 200");
+    }
+
+    #[tokio::test]
+    async fn checks_every_upstream() {
+        let mut config = pinging(backend(&[PONG]).await);
+        config.vhosts.hosts = serde_json::from_value(serde_json::json!([
+            {
+                "names": ["example.com"],
+                "root_dir": "/srv/example",
+                "upstream": { "backends": [backend(&[PONG, BUSY]).await] },
+            }
+        ]))
+        .unwrap();
+        let service = PhpService::new(reload::state(&config, None).await.unwrap());
+        let admin = Admin::new(service, Arc::new(AtomicBool::new(true)));
+
+        let reason = admin.readiness().await.unwrap_err();
+
+        assert_eq!(reason, "example.com: ping failed: status 503, expected 200");
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Cursor, Read, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use blake2::{Blake2s256, Digest};
+use futures::future::BoxFuture;
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, EXPIRES, IF_NONE_MATCH, IF_RANGE},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::body::Bytes;
+use hyper_staticfile::{
+    vfs::{FileAccess, FileOpener, FileWithMetadata, IntoFileAccess, TokioFileAccess},
+    Body, ResolveResult, ResponseBuilder, Static,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncSeek};
+
+use crate::{metrics::METRICS, paths::Paths};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    /// Paths the rule applies to, any path when empty
+    #[serde(default)]
+    pub paths: Paths,
+
+    /// File extensions the rule applies to, any extension when empty
+    #[serde(default)]
+    pub extensions: Vec<String>,
+
+    /// Seconds responses may be cached, `0` requires revalidation
+    pub max_age: u64,
+
+    /// Files never change under the same name, e.g. fingerprinted assets
+    #[serde(default)]
+    pub immutable: bool,
+
+    /// Only the browser may cache responses, not shared caches
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl CacheRule {
+    fn matches(&self, path: &str) -> bool {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+
+        (self.paths.is_empty() || self.paths.is_match(path))
+            && (self.extensions.is_empty()
+                || extension.is_some_and(|ext| self.extensions.iter().any(|e| e == ext)))
+    }
+
+    fn cache_control(&self) -> String {
+        let scope = match self.private {
+            true => "private",
+            false => "public",
+        };
+
+        match (self.max_age, self.immutable) {
+            (0, _) => format!("{scope}, no-cache"),
+            (max_age, false) => format!("{scope}, max-age={max_age}"),
+            (max_age, true) => format!("{scope}, max-age={max_age}, immutable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// `Cache-Control` rules for static files, the first matching rule applies
+    pub cache_control: Vec<CacheRule>,
+
+    /// Small files kept in memory, `0` disables the cache
+    pub cache_entries: usize,
+
+    /// Files up to this many bytes are cached
+    pub cache_max_file_size: u64,
+
+    /// Seconds a cached file is served before it is read from disk again
+    pub cache_ttl: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cache_control: Vec::new(),
+            cache_entries: 1024,
+            cache_max_file_size: 64 * 1024,
+            cache_ttl: 2,
+        }
+    }
+}
+
+struct Entry {
+    content: Bytes,
+    /// Strong ETag from a hash of the content
+    etag: HeaderValue,
+    modified: Option<SystemTime>,
+    loaded: Instant,
+}
+
+fn content_etag(content: &[u8]) -> HeaderValue {
+    let hash = Blake2s256::digest(content);
+    let tag = format!("\"{}\"", URL_SAFE_NO_PAD.encode(&hash[..16]));
+    HeaderValue::try_from(tag).expect("base64 is a valid header value")
+}
+
+/// Contents of small files, saving the `open` and `stat` calls of hot files.
+pub struct FileCache {
+    entries: Mutex<HashMap<PathBuf, Arc<Entry>>>,
+    max_entries: usize,
+    max_file_size: u64,
+    ttl: Duration,
+}
+
+impl FileCache {
+    fn get(&self, path: &Path) -> Option<Arc<Entry>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(path).filter(|e| e.loaded.elapsed() < self.ttl);
+
+        let outcome = match entry {
+            Some(_) => "hit",
+            None => "miss",
+        };
+        METRICS.file_cache.with_label_values(&[outcome]).inc();
+
+        entry.cloned()
+    }
+
+    fn insert(&self, path: PathBuf, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&path) {
+            entries.retain(|_, e| e.loaded.elapsed() < self.ttl);
+        }
+
+        // Still full after dropping expired entries, make room for the new one
+        if entries.len() >= self.max_entries && !entries.contains_key(&path) {
+            if let Some(key) = entries.keys().next().cloned() {
+                entries.remove(&key);
+            }
+        }
+
+        entries.insert(path, Arc::new(entry));
+    }
+}
+
+/// Opens files from disk or from the cache.
+pub struct Opener {
+    root: PathBuf,
+    cache: Option<Arc<FileCache>>,
+}
+
+impl Opener {
+    pub fn new(root: PathBuf, cache: Option<Arc<FileCache>>) -> Self {
+        Self { root, cache }
+    }
+}
+
+impl FileOpener for Opener {
+    type File = Handle;
+    type Future = BoxFuture<'static, io::Result<FileWithMetadata<Handle>>>;
+
+    fn open(&self, path: &Path) -> Self::Future {
+        let mut full_path = self.root.clone();
+        full_path.extend(path);
+
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            if let Some(entry) = cache.as_ref().and_then(|c| c.get(&full_path)) {
+                return Ok(memory(&entry));
+            }
+
+            tokio::task::spawn_blocking(move || {
+                let mut file = OpenOptions::new().read(true).open(&full_path)?;
+                let metadata = file.metadata()?;
+                let modified = metadata.modified().ok();
+
+                match cache {
+                    Some(cache) if metadata.is_file() && metadata.len() <= cache.max_file_size => {
+                        let mut content = Vec::with_capacity(metadata.len() as usize);
+                        file.read_to_end(&mut content)?;
+
+                        let entry = Entry {
+                            etag: content_etag(&content),
+                            content: content.into(),
+                            modified,
+                            loaded: Instant::now(),
+                        };
+                        let file = memory(&entry);
+                        cache.insert(full_path, entry);
+
+                        Ok(file)
+                    }
+                    _ => Ok(FileWithMetadata {
+                        handle: Handle::Disk(File::from_std(file)),
+                        size: metadata.len(),
+                        modified,
+                        is_dir: metadata.is_dir(),
+                    }),
+                }
+            })
+            .await?
+        })
+    }
+}
+
+fn memory(entry: &Entry) -> FileWithMetadata<Handle> {
+    FileWithMetadata {
+        handle: Handle::Memory(entry.content.clone(), entry.etag.clone()),
+        size: entry.content.len() as u64,
+        modified: entry.modified,
+        is_dir: false,
+    }
+}
+
+pub enum Handle {
+    Disk(File),
+    /// Content and strong ETag of a cached file
+    Memory(Bytes, HeaderValue),
+}
+
+impl Handle {
+    fn etag(&self) -> Option<&HeaderValue> {
+        match self {
+            Handle::Disk(_) => None,
+            Handle::Memory(_, etag) => Some(etag),
+        }
+    }
+}
+
+impl IntoFileAccess for Handle {
+    type Output = Access;
+
+    fn into_file_access(self) -> Self::Output {
+        match self {
+            Handle::Disk(file) => Access::Disk(file.into_file_access()),
+            Handle::Memory(content, _) => Access::Memory(Cursor::new(content)),
+        }
+    }
+}
+
+pub enum Access {
+    Disk(TokioFileAccess),
+    Memory(Cursor<Bytes>),
+}
+
+impl AsyncSeek for Access {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match self.get_mut() {
+            Access::Disk(file) => Pin::new(file).start_seek(position),
+            Access::Memory(cursor) => Pin::new(cursor).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match self.get_mut() {
+            Access::Disk(file) => Pin::new(file).poll_complete(cx),
+            Access::Memory(cursor) => Pin::new(cursor).poll_complete(cx),
+        }
+    }
+}
+
+impl FileAccess for Access {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        len: usize,
+    ) -> Poll<io::Result<Bytes>> {
+        match self.get_mut() {
+            Access::Disk(file) => Pin::new(file).poll_read(cx, len),
+            Access::Memory(cursor) => Pin::new(cursor).poll_read(cx, len),
+        }
+    }
+}
+
+/// Serves a static file like [`Static::serve`]. Files held in the cache get a
+/// strong ETag from their content instead of the weak one from size and
+/// modification time.
+pub async fn serve<B>(
+    files: &Static<Opener>,
+    request: &Request<B>,
+) -> io::Result<Response<Body<Access>>> {
+    let result = files.resolver.resolve_request(request).await?;
+    let etag = match &result {
+        ResolveResult::Found(file) => file.handle.etag().cloned(),
+        _ => None,
+    };
+
+    // The builder compares `If-Range` to the weak ETag, a matching strong one
+    // means the file is unchanged and the range can be served
+    let mut headers = request.headers().clone();
+    if etag.is_some() && headers.get(IF_RANGE) == etag.as_ref() {
+        headers.remove(IF_RANGE);
+    }
+
+    let mut response = ResponseBuilder::new()
+        .request_parts(request.method(), request.uri(), &headers)
+        .cache_headers(files.cache_headers)
+        .build(result)
+        .map_err(io::Error::other)?;
+
+    if let Some(etag) = etag.filter(|_| response.headers().contains_key(ETAG)) {
+        response.headers_mut().insert(ETAG, etag);
+    }
+
+    Ok(response)
+}
+
+/// Caching headers and conditional requests for static files.
+pub struct Assets {
+    rules: Vec<CacheRule>,
+    cache: Option<Arc<FileCache>>,
+}
+
+impl Assets {
+    pub fn new(options: Options) -> Self {
+        let cache = (options.cache_entries > 0).then(|| {
+            Arc::new(FileCache {
+                entries: Mutex::new(HashMap::new()),
+                max_entries: options.cache_entries,
+                max_file_size: options.cache_max_file_size,
+                ttl: Duration::from_secs(options.cache_ttl),
+            })
+        });
+
+        Self {
+            rules: options.cache_control,
+            cache,
+        }
+    }
+
+    pub fn cache(&self) -> Option<&Arc<FileCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Answers matching `If-None-Match` requests with 304 and adds the caching
+    /// headers of the first matching rule.
+    pub fn respond<B: Default>(
+        &self,
+        method: &Method,
+        path: &str,
+        request: &HeaderMap,
+        mut response: Response<B>,
+    ) -> Response<B> {
+        let not_modified = matches!(*method, Method::GET | Method::HEAD)
+            && response.status() == StatusCode::OK
+            && response
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .is_some_and(|etag| {
+                    // If-None-Match uses the weak comparison
+                    let etag = etag.trim_start_matches("W/");
+
+                    request
+                        .get_all(IF_NONE_MATCH)
+                        .iter()
+                        .filter_map(|v| v.to_str().ok())
+                        .flat_map(|v| v.split(','))
+                        .map(|v| v.trim())
+                        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+                });
+
+        if not_modified {
+            let (mut parts, _) = response.into_parts();
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(CONTENT_TYPE);
+            response = Response::from_parts(parts, B::default());
+        }
+
+        if !matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+        ) {
+            return response;
+        }
+
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(path)) {
+            let expires = chrono::Utc::now() + Duration::from_secs(rule.max_age);
+            let headers = response.headers_mut();
+
+            headers.insert(CACHE_CONTROL, rule.cache_control().parse().unwrap());
+            headers.insert(
+                EXPIRES,
+                expires
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string()
+                    .parse()
+                    .unwrap(),
+            );
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::IF_NONE_MATCH;
+
+    use super::*;
+
+    fn cache(max_entries: usize, ttl: u64) -> FileCache {
+        FileCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            max_file_size: 1024,
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    fn entry(age: u64) -> Entry {
+        Entry {
+            content: Bytes::from_static(b"x"),
+            etag: content_etag(b"x"),
+            modified: None,
+            loaded: Instant::now() - Duration::from_secs(age),
+        }
+    }
+
+    fn file(etag: &str) -> Response<String> {
+        Response::builder()
+            .header(ETAG, etag)
+            .header(CONTENT_TYPE, "text/css")
+            .body("body".into())
+            .unwrap()
+    }
+
+    /// Static files served from a fresh directory holding `files`.
+    fn site(files: &[(&str, &str)], cache: bool) -> Static<Opener> {
+        let root = std::env::temp_dir().join(format!("pyper-assets-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir(&root).unwrap();
+        for (name, content) in files {
+            std::fs::write(root.join(name), content).unwrap();
+        }
+
+        let assets = Assets::new(Options {
+            cache_entries: if cache { 16 } else { 0 },
+            ..Options::default()
+        });
+        Static::with_opener(Opener::new(root, assets.cache().cloned()))
+    }
+
+    fn get(path: &str, headers: &[(http::HeaderName, &str)]) -> Request<()> {
+        let mut request = Request::get(path);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tags_cached_files_by_content() {
+        let files = site(&[("a.css", "aaaa"), ("b.css", "bbbb")], true);
+
+        let a = serve(&files, &get("/a.css", &[])).await.unwrap();
+        let b = serve(&files, &get("/b.css", &[])).await.unwrap();
+
+        assert_eq!(a.headers()[ETAG], content_etag(b"aaaa"));
+        assert!(!a.headers()[ETAG].as_bytes().starts_with(b"W/"));
+        assert_ne!(a.headers()[ETAG], b.headers()[ETAG]);
+    }
+
+    #[tokio::test]
+    async fn keeps_weak_etags_for_uncached_files() {
+        let files = site(&[("a.css", "aaaa")], false);
+
+        let response = serve(&files, &get("/a.css", &[])).await.unwrap();
+
+        assert!(response.headers()[ETAG].as_bytes().starts_with(b"W/"));
+    }
+
+    #[tokio::test]
+    async fn serves_ranges_for_matching_strong_if_range() {
+        let files = site(&[("a.css", "aaaa")], true);
+        let etag = content_etag(b"aaaa");
+        let etag = etag.to_str().unwrap();
+
+        let range = serve(
+            &files,
+            &get(
+                "/a.css",
+                &[(http::header::RANGE, "bytes=0-1"), (IF_RANGE, etag)],
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+
+        let stale = serve(
+            &files,
+            &get(
+                "/a.css",
+                &[(http::header::RANGE, "bytes=0-1"), (IF_RANGE, "\"old\"")],
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn evicts_only_when_expired_entries_were_not_enough() {
+        let cache = cache(2, 10);
+        cache.insert("/old".into(), entry(60));
+        cache.insert("/fresh".into(), entry(0));
+        cache.insert("/new".into(), entry(0));
+
+        let mut keys: Vec<_> = cache.entries.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [PathBuf::from("/fresh"), PathBuf::from("/new")]);
+
+        cache.insert("/newer".into(), entry(0));
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(cache.get(Path::new("/newer")).is_some());
+    }
+
+    #[test]
+    fn answers_if_none_match_with_weak_etag() {
+        let assets = Assets::new(Options::default());
+        let mut request = HeaderMap::new();
+        request.insert(IF_NONE_MATCH, "\"other\", \"1-2.3\"".parse().unwrap());
+
+        let response = assets.respond(&Method::GET, "/a.css", &request, file("W/\"1-2.3\""));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], "W/\"1-2.3\"");
+        assert!(!response.headers().contains_key(CONTENT_TYPE));
+
+        let response = assets.respond(
+            &Method::GET,
+            "/a.css",
+            &HeaderMap::new(),
+            file("W/\"1-2.3\""),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn adds_cache_headers_of_first_matching_rule() {
+        let assets = Assets::new(Options {
+            cache_control: vec![
+                CacheRule {
+                    paths: serde_json::from_str(r#"["/build/**"]"#).unwrap(),
+                    extensions: vec!["css".into()],
+                    max_age: 31536000,
+                    immutable: true,
+                    private: false,
+                },
+                CacheRule {
+                    paths: Paths::default(),
+                    extensions: Vec::new(),
+                    max_age: 0,
+                    immutable: false,
+                    private: true,
+                },
+            ],
+            ..Options::default()
+        });
+        let cache_control = |path| {
+            assets
+                .respond(&Method::GET, path, &HeaderMap::new(), file("W/\"1\""))
+                .headers()[CACHE_CONTROL]
+                .clone()
+        };
+
+        assert_eq!(
+            cache_control("/build/app.css"),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(cache_control("/build/app.js"), "private, no-cache");
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
+};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::METRICS, paths::Paths};
+
+/// Verified Basic credentials kept per rule before the cache is cleared.
+const MAX_VERIFIED: usize = 1024;
+
+const DEFAULT_REALM: &str = "Restricted";
+
+const BCRYPT_PREFIXES: &[&str] = &["$2a$", "$2b$", "$2y$"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{path}:{line}: {reason}")]
+    Htpasswd {
+        path: PathBuf,
+        line: usize,
+        reason: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Paths requiring authentication, every path when empty
+    #[serde(default)]
+    pub paths: Paths,
+
+    /// Realm shown in the password prompt
+    pub realm: Option<String>,
+
+    /// htpasswd file with bcrypt or argon2 hashes, checked for Basic auth
+    pub htpasswd: Option<PathBuf>,
+
+    /// Tokens accepted as `Authorization: Bearer`, by the user name passed to PHP
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+}
+
+impl Rule {
+    pub fn realm(&self) -> &str {
+        self.realm.as_deref().unwrap_or(DEFAULT_REALM)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Rules by path, the first matching one applies
+    pub rules: Vec<Rule>,
+}
+
+/// How a request was authenticated.
+#[derive(Debug, Clone, Copy)]
+pub enum Scheme {
+    Basic,
+    Bearer,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Basic => "Basic",
+            Scheme::Bearer => "Bearer",
+        }
+    }
+}
+
+/// The authenticated user, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub scheme: Scheme,
+}
+
+/// Compares in constant time so the token can't be guessed byte by byte.
+pub fn token_matches(expected: &str, given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_supported(hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+        || (hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok())
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if !hash.starts_with("$argon2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+/// Password hashes by user, skipping blank lines and `#` comments.
+fn parse_htpasswd(path: &Path, content: &str) -> Result<HashMap<String, String>, Error> {
+    let mut users = HashMap::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |reason| Error::Htpasswd {
+            path: path.into(),
+            line: number + 1,
+            reason,
+        };
+
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected `user:hash`"))?;
+
+        if !is_supported(hash) {
+            return Err(error("unsupported hash, expected bcrypt or argon2"));
+        }
+
+        users.insert(user.to_string(), hash.to_string());
+    }
+
+    Ok(users)
+}
+
+struct Guard {
+    paths: Paths,
+    /// Password hashes by user
+    users: HashMap<String, String>,
+    /// `(user, token)` pairs
+    tokens: Vec<(String, String)>,
+    challenges: HeaderMap,
+    /// Users by `Authorization` header already checked against their hash
+    verified: Mutex<HashMap<HeaderValue, String>>,
+}
+
+impl Guard {
+    async fn new(rule: &Rule) -> Result<Self, Error> {
+        let users = match &rule.htpasswd {
+            Some(path) => {
+                let content =
+                    tokio::fs::read_to_string(path)
+                        .await
+                        .map_err(|source| Error::Read {
+                            path: path.clone(),
+                            source,
+                        })?;
+                parse_htpasswd(path, &content)?
+            }
+            None => HashMap::new(),
+        };
+
+        let mut challenges = HeaderMap::new();
+        let mut challenge = |scheme: &str| {
+            let value = format!("{scheme} realm=\"{}\"", rule.realm());
+            challenges.append(
+                WWW_AUTHENTICATE,
+                HeaderValue::try_from(value).expect("realm validated with the config"),
+            );
+        };
+
+        if rule.htpasswd.is_some() {
+            challenge("Basic");
+        }
+        if !rule.tokens.is_empty() {
+            challenge("Bearer");
+        }
+
+        Ok(Self {
+            paths: rule.paths.clone(),
+            users,
+            tokens: rule.tokens.clone().into_iter().collect(),
+            challenges,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn authenticate(&self, header: &HeaderValue) -> Option<User> {
+        let (scheme, credentials) = header.to_str().ok()?.split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self
+                .tokens
+                .iter()
+                .find(|(_, token)| token_matches(token, credentials.as_bytes()))
+                .map(|(name, _)| User {
+                    name: name.clone(),
+                    scheme: Scheme::Bearer,
+                });
+        }
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let basic = |name: String| User {
+            name,
+            scheme: Scheme::Basic,
+        };
+
+        if let Some(name) = self.verified.lock().unwrap().get(header) {
+            return Some(basic(name.clone()));
+        }
+
+        let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        let hash = self.users.get(name)?.clone();
+        let password = password.to_string();
+
+        // Hashes are deliberately slow to compute, keep them off the runtime threads
+        let valid = tokio::task::spawn_blocking(move || verify(&password, &hash))
+            .await
+            .unwrap_or(false);
+
+        if !valid {
+            return None;
+        }
+
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(header.clone(), name.to_string());
+
+        Some(basic(name.to_string()))
+    }
+
+    fn challenge(&self) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::new(Bytes::from_static(b"unauthorized")));
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        response.headers_mut().extend(self.challenges.clone());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        response
+    }
+}
+
+/// Basic and bearer authentication for static files and PHP alike.
+pub struct Auth {
+    guards: Vec<Guard>,
+}
+
+impl Auth {
+    /// Reads the htpasswd files, returning `None` when no rules are configured.
+    pub async fn load(options: &Options) -> Result<Option<Self>, Error> {
+        if options.rules.is_empty() {
+            return Ok(None);
+        }
+
+        let mut guards = Vec::with_capacity(options.rules.len());
+        for rule in &options.rules {
+            guards.push(Guard::new(rule).await?);
+        }
+
+        Ok(Some(Self { guards }))
+    }
+
+    /// Adds the [`User`] to the request, or returns a 401 challenge when the path
+    /// requires authentication and the credentials are missing or wrong.
+    pub async fn check<B>(&self, request: &mut Request<B>) -> Result<(), Response<Full<Bytes>>> {
+        let path = request.uri().path();
+        let Some(guard) = self
+            .guards
+            .iter()
+            .find(|guard| guard.paths.is_empty() || guard.paths.is_match(path))
+        else {
+            return Ok(());
+        };
+
+        let user = match request.headers().get(AUTHORIZATION) {
+            Some(header) => guard.authenticate(header).await,
+            None => None,
+        };
+
+        match user {
+            Some(user) => {
+                request.extensions_mut().insert(user);
+                Ok(())
+            }
+            None => {
+                METRICS.unauthorized.inc();
+                Err(guard.challenge())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn admin_auth() -> Auth {
+        let rule = Rule {
+            paths: Paths::try_from(vec!["/admin".to_string(), "/admin/**".to_string()]).unwrap(),
+            realm: None,
+            htpasswd: None,
+            tokens: BTreeMap::from([("deploy".to_string(), "secret".to_string())]),
+        };
+        Auth::load(&Options { rules: vec![rule] })
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn request(path: &str) -> Request<()> {
+        Request::builder().uri(path).body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn guards_paths_written_differently() {
+        let auth = admin_auth().await;
+
+        for path in [
+            "/admin",
+            "//admin",
+            "/./admin/",
+            "/public/../admin",
+            "/%61dmin/users",
+        ] {
+            let response = auth.check(&mut request(path)).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+        }
+
+        assert!(auth.check(&mut request("/public")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn accepts_bearer_token() {
+        let auth = admin_auth().await;
+        let mut request = request("/admin");
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+
+        auth.check(&mut request).await.unwrap();
+
+        assert_eq!(request.extensions().get::<User>().unwrap().name, "deploy");
+    }
+
+    #[test]
+    fn parses_htpasswd() {
+        let hash = bcrypt::hash("password", 4).unwrap();
+        let content = format!("# admins\n\nalice:{hash}\n");
+
+        let users = parse_htpasswd(Path::new("htpasswd"), &content).unwrap();
+
+        assert_eq!(users.len(), 1);
+        assert!(verify("password", &users["alice"]));
+        assert!(!verify("wrong", &users["alice"]));
+    }
+
+    #[test]
+    fn rejects_unsupported_hashes() {
+        let content = "# admins\nalice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n";
+
+        let error = parse_htpasswd(Path::new("htpasswd"), content).unwrap_err();
+
+        assert!(matches!(error, Error::Htpasswd { line: 2, .. }), "{error}");
+    }
+}
@@ -0,0 +1,444 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use futures::TryStreamExt;
+use http::{
+    header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+        CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+    },
+    HeaderMap, HeaderValue, Method, Request, StatusCode,
+};
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::{
+    body::{Body, Bytes, Frame},
+    Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::service;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Zstd,
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Br, Encoding::Gzip];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The ETag of the identity response with the coding appended, so caches and
+    /// range requests can tell the representations apart.
+    fn etag(&self, etag: &HeaderValue) -> Option<HeaderValue> {
+        let tag = etag.to_str().ok()?.strip_suffix('"')?;
+        HeaderValue::try_from(format!("{tag}-{}\"", self.as_str())).ok()
+    }
+
+    /// Strips the suffix added by [`Encoding::etag`], returning the identity tag.
+    fn strip_etag(tag: &str) -> Option<(Encoding, String)> {
+        let quoted = tag.strip_suffix('"')?;
+
+        Encoding::ALL.into_iter().find_map(|encoding| {
+            let identity = quoted.strip_suffix(&format!("-{}", encoding.as_str()))?;
+            Some((encoding, format!("{identity}\"")))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Compress responses using an encoding negotiated with the client
+    pub enabled: bool,
+
+    /// Encodings offered to clients, in order of preference
+    pub encodings: Vec<Encoding>,
+
+    /// Responses smaller than this many bytes are sent uncompressed
+    pub min_size: u64,
+
+    /// MIME types eligible for compression, `type/*` matches a whole type
+    pub types: Vec<String>,
+
+    /// Serve precompressed `.br` and `.gz` siblings of static files
+    pub precompressed: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            encodings: vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            min_size: 1024,
+            types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/rss+xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            precompressed: true,
+        }
+    }
+}
+
+/// What a request allows the response to be compressed with.
+pub struct Accept {
+    header: Option<HeaderValue>,
+    head: bool,
+    /// Coding of the ETags in `If-None-Match`, which were replaced by the identity ones
+    validated: Option<Encoding>,
+}
+
+impl Accept {
+    /// Also rewrites the ETags of compressed responses in `If-None-Match` to the
+    /// identity ones, which the file server and PHP compare them with.
+    pub fn new<B>(request: &mut Request<B>) -> Self {
+        let mut validated = None;
+        let tags: Vec<_> = request
+            .headers()
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| match Encoding::strip_etag(tag.trim()) {
+                Some((encoding, identity)) => {
+                    validated = Some(encoding);
+                    identity
+                }
+                None => tag.trim().to_string(),
+            })
+            .collect();
+
+        if validated.is_some() {
+            if let Ok(value) = HeaderValue::try_from(tags.join(", ")) {
+                request.headers_mut().insert(IF_NONE_MATCH, value);
+            }
+        }
+
+        Self {
+            header: request.headers().get(ACCEPT_ENCODING).cloned(),
+            head: request.method() == Method::HEAD,
+            validated,
+        }
+    }
+
+    fn quality(&self, encoding: Encoding) -> Option<f32> {
+        let header = self.header.as_ref()?.to_str().ok()?;
+        let mut wildcard = None;
+
+        for item in header.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if name.eq_ignore_ascii_case(encoding.as_str()) {
+                return Some(q);
+            }
+
+            if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+
+        wildcard
+    }
+}
+
+pub struct Compressor {
+    options: Options,
+}
+
+impl Compressor {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn precompressed(&self) -> bool {
+        self.options.enabled && self.options.precompressed
+    }
+
+    fn is_compressible(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        let (kind, _) = essence.split_once('/').unwrap_or((essence, ""));
+
+        self.options
+            .types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(prefix) => prefix.eq_ignore_ascii_case(kind),
+                None => allowed.eq_ignore_ascii_case(essence),
+            })
+    }
+
+    fn negotiate(&self, accept: &Accept) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for encoding in self.options.encodings.iter().copied() {
+            match accept.quality(encoding) {
+                Some(q) if q > 0.0 && best.is_none_or(|(_, best)| q > best) => {
+                    best = Some((encoding, q));
+                }
+                _ => {}
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn encode(
+        &self,
+        accept: &Accept,
+        mut response: Response<BoxBody<Bytes, service::Error>>,
+    ) -> Response<BoxBody<Bytes, service::Error>> {
+        let status = response.status();
+
+        // The client revalidated a compressed response, answer with its ETag
+        if let (StatusCode::NOT_MODIFIED, Some(encoding)) = (status, accept.validated) {
+            let etag = response.headers().get(ETAG).and_then(|e| encoding.etag(e));
+
+            if let Some(etag) = etag {
+                response.headers_mut().insert(ETAG, etag);
+            }
+        }
+
+        if !self.options.enabled
+            || status.is_informational()
+            || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+            || !self.is_compressible(response.headers())
+        {
+            return response;
+        }
+
+        // Ranges and HEAD responses are never compressed, but a full GET would be
+        let headers = response.headers_mut();
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        if accept.head || status == StatusCode::PARTIAL_CONTENT {
+            return response;
+        }
+
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+
+        if no_transform || headers.contains_key(CONTENT_ENCODING) {
+            return response;
+        }
+
+        let size = response.body().size_hint().exact().or_else(|| {
+            response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        });
+
+        if size.is_some_and(|size| size < self.options.min_size) {
+            return response;
+        }
+
+        let Some(encoding) = self.negotiate(accept) else {
+            return response;
+        };
+
+        tracing::trace!({ encoding = encoding.as_str() }, "compressing response");
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        // Byte ranges of the identity response don't apply to the compressed one
+        parts.headers.remove(ACCEPT_RANGES);
+
+        if let Some(etag) = parts.headers.get(ETAG).and_then(|e| encoding.etag(e)) {
+            parts.headers.insert(ETAG, etag);
+        }
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+
+        let stream = TryStreamExt::map_err(body.into_data_stream(), std::io::Error::other);
+        let reader = StreamReader::new(stream);
+
+        let body = match encoding {
+            Encoding::Zstd => boxed(ZstdEncoder::new(reader)),
+            Encoding::Br => boxed(BrotliEncoder::new(reader)),
+            Encoding::Gzip => boxed(GzipEncoder::new(reader)),
+        };
+
+        Response::from_parts(parts, body)
+    }
+}
+
+fn boxed<R: AsyncRead + Send + Sync + 'static>(reader: R) -> BoxBody<Bytes, service::Error> {
+    let stream = ReaderStream::new(reader)
+        .map_ok(Frame::data)
+        .map_err(service::Error::from);
+
+    StreamBody::new(stream).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::ACCEPT_RANGES;
+    use http_body_util::Full;
+
+    use super::*;
+
+    fn negotiated(accept_encoding: &str, if_none_match: Option<&str>) -> (Accept, Request<()>) {
+        let mut request = Request::builder().header(ACCEPT_ENCODING, accept_encoding);
+        if let Some(tag) = if_none_match {
+            request = request.header(IF_NONE_MATCH, tag);
+        }
+        let mut request = request.body(()).unwrap();
+
+        (Accept::new(&mut request), request)
+    }
+
+    fn response(
+        status: StatusCode,
+        body: &'static str,
+    ) -> Response<BoxBody<Bytes, service::Error>> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/html")
+            .header(ETAG, "\"abc-123\"")
+            .header(ACCEPT_RANGES, "bytes")
+            .body(
+                Full::new(Bytes::from_static(body.as_bytes()))
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn negotiates_highest_quality() {
+        let compressor = Compressor::new(Options::default());
+        let negotiate = |header| compressor.negotiate(&negotiated(header, None).0);
+
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Br));
+        assert_eq!(negotiate("gzip;q=1, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("*;q=0, GZIP"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+    }
+
+    #[test]
+    fn matches_types() {
+        let compressor = Compressor::new(Options::default());
+        let compressible = |content_type| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            compressor.is_compressible(&headers)
+        };
+
+        assert!(compressible("text/css"));
+        assert!(compressible("application/json; charset=utf-8"));
+        assert!(!compressible("image/png"));
+        assert!(!compressible("application/jsonx"));
+    }
+
+    #[test]
+    fn suffixes_etags() {
+        let etag = HeaderValue::from_static("\"abc\"");
+        assert_eq!(Encoding::Gzip.etag(&etag).unwrap(), "\"abc-gzip\"");
+
+        let weak = HeaderValue::from_static("W/\"abc\"");
+        assert_eq!(Encoding::Br.etag(&weak).unwrap(), "W/\"abc-br\"");
+
+        assert_eq!(
+            Encoding::strip_etag("\"abc-zstd\""),
+            Some((Encoding::Zstd, "\"abc\"".to_string()))
+        );
+        assert_eq!(Encoding::strip_etag("\"abc\""), None);
+    }
+
+    #[test]
+    fn rewrites_if_none_match() {
+        let (accept, request) = negotiated("gzip", Some("\"a-gzip\", W/\"b\""));
+
+        assert_eq!(accept.validated, Some(Encoding::Gzip));
+        assert_eq!(request.headers()[IF_NONE_MATCH], "\"a\", W/\"b\"");
+
+        let (accept, request) = negotiated("gzip", Some("\"a\""));
+
+        assert_eq!(accept.validated, None);
+        assert_eq!(request.headers()[IF_NONE_MATCH], "\"a\"");
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_get_their_own_etag() {
+        let compressor = Compressor::new(Options {
+            min_size: 0,
+            ..Default::default()
+        });
+        let (accept, _) = negotiated("gzip", None);
+        let response = compressor.encode(&accept, response(StatusCode::OK, "hello"));
+        let headers = response.headers();
+
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[ETAG], "\"abc-123-gzip\"");
+        assert!(!headers.contains_key(ACCEPT_RANGES));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn identity_responses_keep_their_etag() {
+        let compressor = Compressor::new(Options::default());
+        let (accept, _) = negotiated("gzip", None);
+        let response = compressor.encode(&accept, response(StatusCode::OK, "tiny"));
+        let headers = response.headers();
+
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(headers[ETAG], "\"abc-123\"");
+        assert_eq!(headers[ACCEPT_RANGES], "bytes");
+        assert_eq!(headers[VARY], "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn revalidated_compressed_responses_keep_the_suffix() {
+        let compressor = Compressor::new(Options::default());
+        let (accept, _) = negotiated("gzip", Some("\"abc-123-gzip\""));
+        let response = compressor.encode(&accept, response(StatusCode::NOT_MODIFIED, ""));
+
+        assert_eq!(response.headers()[ETAG], "\"abc-123-gzip\"");
+    }
+
+    #[tokio::test]
+    async fn ranges_vary_but_stay_identity() {
+        let compressor = Compressor::new(Options {
+            min_size: 0,
+            ..Default::default()
+        });
+        let (accept, _) = negotiated("gzip", None);
+        let response = compressor.encode(&accept, response(StatusCode::PARTIAL_CONTENT, "hel"));
+        let headers = response.headers();
+
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(headers[VARY], "accept-encoding");
+        assert_eq!(headers[ETAG], "\"abc-123\"");
+    }
+}
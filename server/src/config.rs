@@ -0,0 +1,627 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    access_log, admin, assets, auth, compress, cors, headers, internal, maintenance, manager,
+    overload, rate_limit, request_id, retry, supervisor, telemetry, upstream, vhost,
+};
+
+const ENV_PREFIX: &str = "PYPER_";
+const ENV_CONFIG: &str = "PYPER_CONFIG";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unsupported config file {0}, expected a .toml, .yaml or .yml extension")]
+    Extension(PathBuf),
+    #[error("invalid config file {path}:\n{source}")]
+    Toml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid config file {path}: {source}")]
+    Yaml {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+    #[error("invalid environment variable {name}: {source}")]
+    Env {
+        name: String,
+        source: serde_json::Error,
+    },
+    #[error("invalid value for `{key}`: {reason}")]
+    Invalid { key: &'static str, reason: String },
+    #[error("failed to print config: {0}")]
+    Print(String),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+/// Flags taking precedence over both the config file and the environment.
+#[derive(Args, Clone)]
+#[group(id = "overrides")]
+pub struct Overrides {
+    #[clap(long, global = true)]
+    bind: Option<SocketAddr>,
+
+    #[clap(long, global = true)]
+    ping_path: Option<String>,
+
+    #[clap(long, global = true)]
+    listen: Option<SocketAddr>,
+
+    #[clap(long, global = true)]
+    root_dir: Option<PathBuf>,
+
+    #[clap(long, global = true)]
+    max_conn: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub root_dir: PathBuf,
+
+    /// Seconds to keep serving after a shutdown signal while readiness is failing
+    pub shutdown_delay: u64,
+
+    /// Seconds to wait for in-flight connections to finish before exiting
+    pub shutdown_timeout: u64,
+
+    pub fastcgi: manager::Options,
+    pub upstream: upstream::Options,
+    pub retry: retry::Options,
+    pub overload: overload::Options,
+    pub rate_limit: rate_limit::Options,
+    pub supervisor: supervisor::Options,
+    pub admin: admin::Options,
+    pub compress: compress::Options,
+    pub internal: internal::Options,
+    pub access_log: access_log::Options,
+    pub vhosts: vhost::Options,
+    pub assets: assets::Options,
+    pub request_id: request_id::Options,
+    pub maintenance: maintenance::Options,
+    pub auth: auth::Options,
+    pub cors: cors::Options,
+    pub telemetry: telemetry::Options,
+
+    /// Response headers by path, for PHP and static responses
+    pub headers: Vec<headers::Location>,
+
+    /// Request header, FastCGI param and response header rules by path
+    pub rewrites: Vec<headers::Rule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            root_dir: PathBuf::from("./"),
+            shutdown_delay: 5,
+            shutdown_timeout: 30,
+            fastcgi: Default::default(),
+            upstream: Default::default(),
+            retry: Default::default(),
+            overload: Default::default(),
+            rate_limit: Default::default(),
+            supervisor: Default::default(),
+            admin: Default::default(),
+            compress: Default::default(),
+            internal: Default::default(),
+            access_log: Default::default(),
+            vhosts: Default::default(),
+            assets: Default::default(),
+            request_id: Default::default(),
+            maintenance: Default::default(),
+            auth: Default::default(),
+            cors: Default::default(),
+            telemetry: Default::default(),
+            headers: Vec::new(),
+            rewrites: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the config from defaults, the optional file, `PYPER_*` environment
+    /// variables and flags, each overriding the previous.
+    pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Self, Error> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config = config.with_env(std::env::vars())?;
+
+        let Overrides {
+            bind,
+            ping_path,
+            listen,
+            root_dir,
+            max_conn,
+        } = overrides.clone();
+
+        if let Some(bind) = bind {
+            config.fastcgi.bind = bind;
+        }
+        if ping_path.is_some() {
+            config.fastcgi.ping_path = ping_path;
+        }
+        if let Some(listen) = listen {
+            config.listen = listen;
+        }
+        if let Some(root_dir) = root_dir {
+            config.root_dir = root_dir;
+        }
+        if let Some(max_conn) = max_conn {
+            config.fastcgi.max_conn = max_conn;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Whether any rule is limited to request paths, which are then matched in
+    /// their normalized form.
+    pub fn has_path_rules(&self) -> bool {
+        self.auth.rules.iter().any(|rule| !rule.paths.is_empty())
+            || !self.cors.paths.is_empty()
+            || self
+                .rate_limit
+                .rules
+                .iter()
+                .any(|rule| !rule.paths.is_empty())
+            || self
+                .headers
+                .iter()
+                .any(|location| !location.paths.is_empty())
+            || self.rewrites.iter().any(|rule| !rule.paths.is_empty())
+            || self
+                .assets
+                .cache_control
+                .iter()
+                .any(|rule| !rule.paths.is_empty())
+            || !self.overload.priority_paths.is_empty()
+            || self
+                .vhosts
+                .hosts
+                .iter()
+                .any(|host| !host.static_paths.is_empty())
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(|source| Error::Read {
+            path: path.into(),
+            source,
+        })?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|source| Error::Toml {
+                path: path.into(),
+                source,
+            }),
+            Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|source| Error::Yaml {
+                path: path.into(),
+                source,
+            }),
+            _ => Err(Error::Extension(path.into())),
+        }
+    }
+
+    /// Applies variables like `PYPER_LISTEN` or `PYPER_FASTCGI__MAX_CONN`, where `__`
+    /// separates nested keys. Values are parsed as JSON and otherwise taken as strings.
+    /// Variables not naming a top-level key, like the `PYPER_PORT` Kubernetes sets for
+    /// a service called `pyper`, are skipped.
+    fn with_env(self, vars: impl Iterator<Item = (String, String)>) -> Result<Self, Error> {
+        let mut vars: Vec<_> = vars
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != ENV_CONFIG)
+            .collect();
+
+        if vars.is_empty() {
+            return Ok(self);
+        }
+
+        vars.sort();
+
+        let mut tree = serde_json::to_value(&self).expect("config serializes to JSON");
+
+        for (name, raw) in vars {
+            let keys: Vec<String> = name[ENV_PREFIX.len()..]
+                .to_lowercase()
+                .split("__")
+                .map(String::from)
+                .collect();
+
+            if tree.get(&keys[0]).is_none() {
+                tracing::debug!({ %name }, "skipping environment variable without a config key");
+                continue;
+            }
+
+            let parsed = serde_json::from_str(&raw).unwrap_or(Value::String(raw.clone()));
+
+            set(&mut tree, &keys, parsed.clone());
+
+            if let Err(source) = serde_json::from_value::<Self>(tree.clone()) {
+                // Values like `PYPER_ROOT_DIR=1` parse as JSON but are meant as strings
+                if parsed.is_string() {
+                    return Err(Error::Env { name, source });
+                }
+
+                set(&mut tree, &keys, Value::String(raw));
+                serde_json::from_value::<Self>(tree.clone())
+                    .map_err(|source| Error::Env { name, source })?;
+            }
+        }
+
+        Ok(serde_json::from_value(tree).expect("validated above"))
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.fastcgi.max_conn == 0 {
+            return Err(Error::Invalid {
+                key: "fastcgi.max_conn",
+                reason: "must be at least 1".into(),
+            });
+        }
+
+        if self.fastcgi.min_idle > self.fastcgi.max_conn {
+            return Err(Error::Invalid {
+                key: "fastcgi.min_idle",
+                reason: format!("must not exceed max_conn ({})", self.fastcgi.max_conn),
+            });
+        }
+
+        if !(100..=599).contains(&self.fastcgi.ping_status) {
+            return Err(Error::Invalid {
+                key: "fastcgi.ping_status",
+                reason: format!("{} is not an HTTP status", self.fastcgi.ping_status),
+            });
+        }
+
+        if self.fastcgi.ping_timeout_ms == 0 {
+            return Err(Error::Invalid {
+                key: "fastcgi.ping_timeout_ms",
+                reason: "must be at least 1".into(),
+            });
+        }
+
+        if self.fastcgi.connect_timeout_ms == 0 {
+            return Err(Error::Invalid {
+                key: "fastcgi.connect_timeout_ms",
+                reason: "must be at least 1".into(),
+            });
+        }
+
+        if let upstream::HashKey::Header(name) = &self.upstream.hash_key {
+            if let Err(e) = http::HeaderName::try_from(name) {
+                return Err(Error::Invalid {
+                    key: "upstream.hash_key",
+                    reason: e.to_string(),
+                });
+            }
+        }
+
+        for rule in &self.rate_limit.rules {
+            if rule.rate <= 0.0 || !rule.rate.is_finite() {
+                return Err(Error::Invalid {
+                    key: "rate_limit.rules.rate",
+                    reason: format!("must be a positive number, got {}", rule.rate),
+                });
+            }
+
+            if rule.burst == 0 {
+                return Err(Error::Invalid {
+                    key: "rate_limit.rules.burst",
+                    reason: "must be at least 1".into(),
+                });
+            }
+
+            if let rate_limit::Key::Header(name) = &rule.key {
+                if let Err(e) = http::HeaderName::try_from(name) {
+                    return Err(Error::Invalid {
+                        key: "rate_limit.rules.key",
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        for host in &self.vhosts.hosts {
+            if host.names.is_empty() {
+                return Err(Error::Invalid {
+                    key: "vhosts.hosts.names",
+                    reason: format!("no names for {}", host.root_dir.display()),
+                });
+            }
+
+            let valid = |name: &&String| {
+                let rest = name.strip_prefix("*.").unwrap_or(name);
+                !rest.is_empty() && !rest.contains('*')
+            };
+
+            if let Some(name) = host.names.iter().find(|name| !valid(name)) {
+                return Err(Error::Invalid {
+                    key: "vhosts.hosts.names",
+                    reason: format!("`{name}` is not a host name or `*.` wildcard"),
+                });
+            }
+
+            if let Err(reason) = host.headers() {
+                return Err(Error::Invalid {
+                    key: "vhosts.hosts.headers",
+                    reason,
+                });
+            }
+
+            if host
+                .fastcgi
+                .as_ref()
+                .is_some_and(|fastcgi| fastcgi.max_conn == 0)
+            {
+                return Err(Error::Invalid {
+                    key: "vhosts.hosts.fastcgi.max_conn",
+                    reason: "must be at least 1".into(),
+                });
+            }
+
+            if host
+                .fastcgi
+                .as_ref()
+                .is_some_and(|fastcgi| fastcgi.min_idle > fastcgi.max_conn)
+            {
+                return Err(Error::Invalid {
+                    key: "vhosts.hosts.fastcgi.min_idle",
+                    reason: "must not exceed max_conn".into(),
+                });
+            }
+        }
+
+        if let Err(reason) = headers::Locations::new(&self.headers) {
+            return Err(Error::Invalid {
+                key: "headers",
+                reason,
+            });
+        }
+
+        if let Err(reason) = headers::Rewrites::new(&self.rewrites) {
+            return Err(Error::Invalid {
+                key: "rewrites",
+                reason,
+            });
+        }
+
+        if let Err(reason) = headers::parse(&self.maintenance.bypass_headers) {
+            return Err(Error::Invalid {
+                key: "maintenance.bypass_headers",
+                reason,
+            });
+        }
+
+        for rule in &self.auth.rules {
+            if rule.htpasswd.is_none() && rule.tokens.is_empty() {
+                return Err(Error::Invalid {
+                    key: "auth.rules",
+                    reason: "needs an htpasswd file or tokens".into(),
+                });
+            }
+
+            if rule.realm().contains(['"', '\\'])
+                || http::HeaderValue::try_from(rule.realm()).is_err()
+            {
+                return Err(Error::Invalid {
+                    key: "auth.rules.realm",
+                    reason: format!("`{}` can't be used in a header", rule.realm()),
+                });
+            }
+
+            if let Some(name) = rule.tokens.iter().find_map(|(name, token)| {
+                (token.is_empty() || token.contains(char::is_whitespace)).then_some(name)
+            }) {
+                return Err(Error::Invalid {
+                    key: "auth.rules.tokens",
+                    reason: format!("token of `{name}` is empty or contains whitespace"),
+                });
+            }
+        }
+
+        if let Err(reason) = cors::Cors::new(&self.cors) {
+            return Err(Error::Invalid {
+                key: "cors",
+                reason,
+            });
+        }
+
+        if let Err(reason) = self.supervisor.stop_signal() {
+            return Err(Error::Invalid {
+                key: "supervisor.stop_signal",
+                reason,
+            });
+        }
+
+        if !(0.0..=1.0).contains(&self.access_log.sample) {
+            return Err(Error::Invalid {
+                key: "access_log.sample",
+                reason: format!("must be between 0 and 1, got {}", self.access_log.sample),
+            });
+        }
+
+        if let Err(e) = http::HeaderName::try_from(&self.request_id.header) {
+            return Err(Error::Invalid {
+                key: "request_id.header",
+                reason: e.to_string(),
+            });
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            return Err(Error::Invalid {
+                key: "telemetry.sample_ratio",
+                reason: format!(
+                    "must be between 0 and 1, got {}",
+                    self.telemetry.sample_ratio
+                ),
+            });
+        }
+
+        if let Some(location) = self
+            .internal
+            .accel_locations
+            .iter()
+            .find(|location| !location.prefix.starts_with('/'))
+        {
+            return Err(Error::Invalid {
+                key: "internal.accel_locations",
+                reason: format!("prefix must start with `/`, got `{}`", location.prefix),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn print(&self, format: Format) -> Result<String, Error> {
+        match format {
+            Format::Toml => toml::to_string_pretty(self).map_err(|e| Error::Print(e.to_string())),
+            Format::Yaml => serde_yaml::to_string(self).map_err(|e| Error::Print(e.to_string())),
+        }
+    }
+}
+
+fn set(tree: &mut Value, keys: &[String], value: Value) {
+    let Some((last, parents)) = keys.split_last() else {
+        return;
+    };
+
+    let mut node = tree;
+
+    for key in parents {
+        if !node.is_object() {
+            *node = Value::Object(Default::default());
+        }
+
+        node = node
+            .as_object_mut()
+            .expect("checked above")
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+
+    if !node.is_object() {
+        *node = Value::Object(Default::default());
+    }
+
+    node.as_object_mut()
+        .expect("checked above")
+        .insert(last.clone(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Result<Config, Error> {
+        Config::default().with_env(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    #[test]
+    fn applies_nested_keys() {
+        let config = env(&[
+            ("PYPER_LISTEN", "0.0.0.0:8080"),
+            ("PYPER_FASTCGI__MAX_CONN", "7"),
+            ("PYPER_ADMIN__TOKEN", "secret"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.listen, SocketAddr::from(([0, 0, 0, 0], 8080)));
+        assert_eq!(config.fastcgi.max_conn, 7);
+        assert_eq!(config.admin.token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn parses_json_values() {
+        let config = env(&[
+            (
+                "PYPER_CORS__ALLOWED_ORIGINS",
+                r#"["https://a.example", "*"]"#,
+            ),
+            ("PYPER_ROOT_DIR", "1"),
+            ("PYPER_ADMIN__TOKEN", "[not json"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.cors.allowed_origins, ["https://a.example", "*"]);
+        assert_eq!(config.root_dir, PathBuf::from("1"));
+        assert_eq!(config.admin.token.as_deref(), Some("[not json"));
+    }
+
+    #[test]
+    fn skips_unknown_keys() {
+        let config = env(&[
+            ("PYPER_PORT", "tcp://10.0.0.1:80"),
+            ("PYPER_SERVICE_HOST", "10.0.0.1"),
+            ("PYPER_PORT_80_TCP_ADDR", "10.0.0.1"),
+            ("PYPER_CONFIG", "/etc/pyper.toml"),
+            ("HOME", "/root"),
+        ])
+        .unwrap();
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn detects_path_rules() {
+        let mut config = Config::default();
+        assert!(!config.has_path_rules());
+
+        config.overload.priority_paths = vec!["/health.php".to_string()].try_into().unwrap();
+        assert!(config.has_path_rules());
+    }
+
+    #[test]
+    fn rejects_empty_burst() {
+        let mut config = Config::default();
+        config.rate_limit.rules.push(rate_limit::Rule {
+            paths: Default::default(),
+            key: Default::default(),
+            rate: 1.0,
+            burst: 0,
+        });
+
+        assert!(matches!(
+            config.validate(),
+            Err(Error::Invalid {
+                key: "rate_limit.rules.burst",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(matches!(
+            env(&[("PYPER_FASTCGI__MAX_CONN", "many")]),
+            Err(Error::Env { name, .. }) if name == "PYPER_FASTCGI__MAX_CONN"
+        ));
+        assert!(matches!(
+            env(&[("PYPER_FASTCGI__BOGUS", "1")]),
+            Err(Error::Env { .. })
+        ));
+    }
+}
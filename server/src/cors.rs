@@ -0,0 +1,318 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::paths::Paths;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Paths answering cross-origin requests, every path when empty
+    pub paths: Paths,
+
+    /// Origins like `https://app.example.com` or `https://*.example.com`, `*` for
+    /// any, disabled when empty
+    pub allowed_origins: Vec<String>,
+
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers allowed by preflights, `*` allows any
+    pub allowed_headers: Vec<String>,
+
+    /// Response headers readable by scripts besides the safelisted ones
+    pub exposed_headers: Vec<String>,
+
+    /// Whether requests may carry cookies and credentials
+    pub allow_credentials: bool,
+
+    /// Seconds browsers may cache a preflight response
+    pub max_age: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            paths: Paths::default(),
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".into(), "HEAD".into(), "POST".into()],
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+fn join(values: &[String]) -> Result<Option<HeaderValue>, String> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+
+    HeaderValue::try_from(values.join(", "))
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// Marks preflight responses, which already carry their CORS headers.
+#[derive(Clone, Copy)]
+struct Preflight;
+
+/// Answers preflights and adds the CORS headers to responses for allowed origins.
+pub struct Cors {
+    paths: Paths,
+    any_origin: bool,
+    origins: GlobSet,
+    methods: Vec<Method>,
+    allow_methods: HeaderValue,
+    /// Lowercase names, `None` when any header is allowed
+    headers: Option<Vec<HeaderName>>,
+    allow_headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+impl Cors {
+    pub fn new(options: &Options) -> Result<Self, String> {
+        let mut origins = GlobSetBuilder::new();
+
+        for origin in options.allowed_origins.iter().filter(|o| *o != "*") {
+            let glob = GlobBuilder::new(origin)
+                .literal_separator(true)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| e.to_string())?;
+            origins.add(glob);
+        }
+
+        let methods = options
+            .allowed_methods
+            .iter()
+            .map(|method| Method::try_from(method.as_str()).map_err(|e| format!("`{method}`: {e}")))
+            .collect::<Result<_, _>>()?;
+
+        let headers = match options.allowed_headers.iter().any(|h| h == "*") {
+            true => None,
+            false => Some(
+                options
+                    .allowed_headers
+                    .iter()
+                    .map(|name| HeaderName::try_from(name).map_err(|e| format!("`{name}`: {e}")))
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        let allow_headers = match headers {
+            Some(_) => join(&options.allowed_headers)?,
+            None => None,
+        };
+
+        Ok(Self {
+            paths: options.paths.clone(),
+            any_origin: options.allowed_origins.iter().any(|o| o == "*"),
+            origins: origins.build().map_err(|e| e.to_string())?,
+            methods,
+            allow_methods: join(&options.allowed_methods)?.unwrap_or(HeaderValue::from_static("")),
+            allow_headers,
+            headers,
+            expose_headers: join(&options.exposed_headers)?,
+            credentials: options.allow_credentials,
+            max_age: options.max_age.map(HeaderValue::from),
+        })
+    }
+
+    fn applies(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.is_match(path)
+    }
+
+    /// The value for `Access-Control-Allow-Origin`, `None` when the origin is not allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        // Credentialed requests can't use the wildcard, the origin is echoed instead
+        if self.any_origin && !self.credentials {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let allowed = self.any_origin || self.origins.is_match(origin.to_str().ok()?);
+        allowed.then(|| origin.clone())
+    }
+
+    fn add_origin(&self, allow_origin: HeaderValue, headers: &mut HeaderMap) {
+        if allow_origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    /// Whether the preflight asks for a method and headers that are all allowed.
+    fn permits<B>(&self, request: &Request<B>, method: &HeaderValue) -> bool {
+        let method_allowed = self
+            .methods
+            .iter()
+            .any(|allowed| allowed.as_str().as_bytes() == method.as_bytes());
+
+        let Some(allowed) = &self.headers else {
+            return method_allowed;
+        };
+
+        method_allowed
+            && request
+                .headers()
+                .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .all(|name| {
+                    allowed
+                        .iter()
+                        .any(|h| h.as_str().eq_ignore_ascii_case(name))
+                })
+    }
+
+    /// Answers a preflight request, which is never passed on to PHP.
+    pub fn preflight<B>(&self, request: &Request<B>) -> Option<Response<Full<Bytes>>> {
+        let headers = request.headers();
+
+        if request.method() != Method::OPTIONS || !self.applies(request.uri().path()) {
+            return None;
+        }
+
+        let (Some(origin), Some(method)) = (
+            headers.get(ORIGIN),
+            headers.get(ACCESS_CONTROL_REQUEST_METHOD),
+        ) else {
+            return None;
+        };
+
+        let mut response = Response::new(Full::new(Bytes::new()));
+        response.extensions_mut().insert(Preflight);
+
+        let allow_origin = self
+            .allow_origin(origin)
+            .filter(|_| self.permits(request, method));
+
+        let Some(allow_origin) = allow_origin else {
+            tracing::debug!({ ?origin, ?method }, "rejecting preflight");
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            return Some(response);
+        };
+
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let out = response.headers_mut();
+        self.add_origin(allow_origin, out);
+        out.insert(ACCESS_CONTROL_ALLOW_METHODS, self.allow_methods.clone());
+
+        let allow_headers = match &self.headers {
+            Some(_) => self.allow_headers.clone(),
+            None => headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(allow_headers) = allow_headers {
+            out.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = &self.max_age {
+            out.insert(ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+
+        Some(response)
+    }
+
+    /// Adds the CORS headers to the response of a request from `origin` to `path`.
+    pub fn apply<B>(&self, origin: Option<&HeaderValue>, path: &str, response: &mut Response<B>) {
+        let Some(origin) = origin.filter(|_| self.applies(path)) else {
+            return;
+        };
+
+        if response.extensions().get::<Preflight>().is_some() {
+            return;
+        }
+
+        let headers = response.headers_mut();
+
+        if let Some(allow_origin) = self.allow_origin(origin) {
+            self.add_origin(allow_origin, headers);
+
+            if let Some(expose) = &self.expose_headers {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose.clone());
+            }
+        } else {
+            // Caches must not reuse the response for an allowed origin either
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], credentials: bool) -> Cors {
+        Cors::new(&Options {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials: credentials,
+            ..Options::default()
+        })
+        .unwrap()
+    }
+
+    fn allow_origin(cors: &Cors, origin: &'static str) -> Option<HeaderValue> {
+        cors.allow_origin(&HeaderValue::from_static(origin))
+    }
+
+    #[test]
+    fn matches_origin_globs() {
+        let cors = cors(&["https://app.example.com", "https://*.example.org"], false);
+
+        assert_eq!(
+            allow_origin(&cors, "https://app.example.com").unwrap(),
+            "https://app.example.com"
+        );
+        assert!(allow_origin(&cors, "https://API.example.org").is_some());
+        assert!(allow_origin(&cors, "https://example.org").is_none());
+        assert!(allow_origin(&cors, "https://evil.com/.example.org").is_none());
+        assert!(allow_origin(&cors, "http://app.example.com").is_none());
+    }
+
+    #[test]
+    fn echoes_any_origin_with_credentials() {
+        assert_eq!(
+            allow_origin(&cors(&["*"], false), "https://a.test").unwrap(),
+            "*"
+        );
+        assert_eq!(
+            allow_origin(&cors(&["*"], true), "https://a.test").unwrap(),
+            "https://a.test"
+        );
+    }
+
+    #[test]
+    fn applies_to_normalized_paths() {
+        let cors = Cors::new(&Options {
+            paths: Paths::try_from(vec!["/api/**".to_string()]).unwrap(),
+            allowed_origins: vec!["*".to_string()],
+            ..Options::default()
+        })
+        .unwrap();
+
+        assert!(cors.applies("//api/users"));
+        assert!(cors.applies("/%61pi/users"));
+        assert!(!cors.applies("/api/../admin"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::upstream::Backend;
+
+/// Values of php-fpm's status page in JSON format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub pool: String,
+    #[serde(rename(deserialize = "process manager"))]
+    pub process_manager: String,
+    #[serde(rename(deserialize = "accepted conn"))]
+    pub accepted_conn: u64,
+    /// Connections waiting for a free process
+    #[serde(rename(deserialize = "listen queue"))]
+    pub listen_queue: u64,
+    #[serde(rename(deserialize = "max listen queue"))]
+    pub max_listen_queue: u64,
+    #[serde(rename(deserialize = "listen queue len"))]
+    pub listen_queue_len: u64,
+    #[serde(rename(deserialize = "idle processes"))]
+    pub idle_processes: u64,
+    #[serde(rename(deserialize = "active processes"))]
+    pub active_processes: u64,
+    #[serde(rename(deserialize = "total processes"))]
+    pub total_processes: u64,
+    #[serde(rename(deserialize = "max children reached"))]
+    pub max_children_reached: u64,
+    #[serde(rename(deserialize = "slow requests"))]
+    pub slow_requests: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to connect: {0}")]
+    Connect(crate::manager::Error),
+    #[error("failed to request status page: {0}")]
+    Request(#[from] crate::manager::Error),
+    #[error("failed to parse status page: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("status page answered with {0}")]
+    Status(u16),
+    #[error("timed out")]
+    Timeout,
+}
+
+/// Reads the status page over a connection of its own, so scrapes neither wait
+/// for nor take connections meant for requests.
+async fn scrape(backend: &Backend, path: &str) -> Result<Status, Error> {
+    let mut conn = backend
+        .pool()
+        .dedicated_connection()
+        .await
+        .map_err(Error::Connect)?;
+    let reply = conn.get(path, "json", &BTreeMap::new()).await?;
+
+    if reply.status != 200 {
+        return Err(Error::Status(reply.status));
+    }
+
+    Ok(serde_json::from_slice(&reply.body)?)
+}
+
+/// Reads the status page of a backend every `interval` until the backend was
+/// replaced by a reload. Failures clear the last status, a scrape that timed out
+/// keeps it, as a busy pool answers late rather than not at all.
+pub async fn watch(backend: Weak<Backend>, path: String, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+
+    loop {
+        ticks.tick().await;
+
+        let Some(backend) = backend.upgrade() else {
+            return;
+        };
+
+        let result = tokio::time::timeout(interval, scrape(&backend, &path))
+            .await
+            .unwrap_or(Err(Error::Timeout));
+
+        match result {
+            Ok(status) => backend.set_fpm_status(Some(Arc::new(status))),
+            Err(Error::Timeout) => {
+                tracing::warn!({ backend = %backend.addr() }, "php-fpm status timed out, keeping the last one");
+            }
+            Err(e) => {
+                tracing::warn!({ backend = %backend.addr(), error = %e }, "failed to read php-fpm status");
+                backend.set_fpm_status(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manager, upstream};
+
+    fn status() -> Status {
+        Status {
+            pool: "www".to_string(),
+            process_manager: "dynamic".to_string(),
+            accepted_conn: 1,
+            listen_queue: 0,
+            max_listen_queue: 0,
+            listen_queue_len: 0,
+            idle_processes: 1,
+            active_processes: 0,
+            total_processes: 1,
+            max_children_reached: 0,
+            slow_requests: 0,
+        }
+    }
+
+    fn upstream(addr: std::net::SocketAddr) -> upstream::Upstream {
+        upstream::Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &manager::Options::default(),
+            &upstream::Options {
+                backends: vec![addr],
+                ..upstream::Options::default()
+            },
+        )
+    }
+
+    async fn watch_briefly(backend: &Arc<Backend>) {
+        let interval = Duration::from_millis(100);
+        let watch = watch(Arc::downgrade(backend), "/status".to_string(), interval);
+        let _ = tokio::time::timeout(Duration::from_millis(250), watch).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_status_when_scrape_times_out() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = upstream(listener.local_addr().unwrap());
+        let backend = &upstream.backends()[0];
+        backend.set_fpm_status(Some(Arc::new(status())));
+
+        watch_briefly(backend).await;
+
+        assert_eq!(backend.fpm_status().unwrap().pool, "www");
+        assert_eq!(backend.pool().state().connections, 0);
+    }
+
+    #[tokio::test]
+    async fn clears_status_when_backend_refuses() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = upstream(closed);
+        let backend = &upstream.backends()[0];
+        backend.set_fpm_status(Some(Arc::new(status())));
+
+        watch_briefly(backend).await;
+
+        assert!(backend.fpm_status().is_none());
+    }
+}
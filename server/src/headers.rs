@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{paths::Paths, request::ExtraParams};
+
+/// Response headers for paths, e.g. security headers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Location {
+    /// Paths the headers are added to, any path when empty
+    #[serde(default)]
+    pub paths: Paths,
+
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Changes to a set of headers, applied as remove, set, then append.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Edit {
+    /// Headers replacing any with the same name
+    pub set: BTreeMap<String, String>,
+
+    /// Headers added next to any with the same name
+    pub append: BTreeMap<String, String>,
+
+    pub remove: Vec<String>,
+}
+
+/// Rewrites request headers, FastCGI params and response headers for paths.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Paths the rule applies to, any path when empty
+    #[serde(default)]
+    pub paths: Paths,
+
+    /// Applied to the request before it is served
+    #[serde(default)]
+    pub request: Edit,
+
+    /// FastCGI params for PHP, where `${NAME}` is replaced by the environment variable
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+
+    /// Applied to PHP and static responses
+    #[serde(default)]
+    pub response: Edit,
+}
+
+/// Parses a table of header names and values.
+pub fn parse(table: &BTreeMap<String, String>) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in table {
+        let name = HeaderName::try_from(name).map_err(|e| format!("`{name}`: {e}"))?;
+        let value = HeaderValue::try_from(value).map_err(|e| format!("`{name}`: {e}"))?;
+        headers.insert(name, value);
+    }
+
+    Ok(headers)
+}
+
+/// Adds the headers of all matching locations to responses.
+#[derive(Default)]
+pub struct Locations(Vec<(Paths, HeaderMap)>);
+
+impl Locations {
+    pub fn new(locations: &[Location]) -> Result<Self, String> {
+        locations
+            .iter()
+            .map(|location| Ok((location.paths.clone(), parse(&location.headers)?)))
+            .collect::<Result<_, String>>()
+            .map(Self)
+    }
+
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for (paths, extra) in &self.0 {
+            if paths.is_empty() || paths.is_match(path) {
+                headers.extend(extra.clone());
+            }
+        }
+    }
+}
+
+/// Replaces `${NAME}` with the value of the environment variable `NAME`.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `${{` in `{value}`"))?;
+        let name = &rest[start + 2..start + end];
+        let var =
+            std::env::var(name).map_err(|_| format!("environment variable `{name}` is not set"))?;
+
+        out.push_str(&rest[..start]);
+        out.push_str(&var);
+        rest = &rest[start + end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+struct Edits {
+    set: HeaderMap,
+    append: HeaderMap,
+    remove: Vec<HeaderName>,
+}
+
+impl Edits {
+    fn new(edit: &Edit) -> Result<Self, String> {
+        Ok(Self {
+            set: parse(&edit.set)?,
+            append: parse(&edit.append)?,
+            remove: edit
+                .remove
+                .iter()
+                .map(|name| HeaderName::try_from(name).map_err(|e| format!("`{name}`: {e}")))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name, value.clone());
+        }
+        for (name, value) in &self.append {
+            headers.append(name, value.clone());
+        }
+    }
+}
+
+struct Rewrite {
+    paths: Paths,
+    request: Edits,
+    params: BTreeMap<String, String>,
+    response: Edits,
+}
+
+/// Applies the rules matching a path, in order.
+#[derive(Default)]
+pub struct Rewrites(Vec<Rewrite>);
+
+impl Rewrites {
+    pub fn new(rules: &[Rule]) -> Result<Self, String> {
+        rules
+            .iter()
+            .map(|rule| {
+                Ok(Rewrite {
+                    paths: rule.paths.clone(),
+                    request: Edits::new(&rule.request)?,
+                    params: rule
+                        .params
+                        .iter()
+                        .map(|(name, value)| Ok((name.clone(), interpolate(value)?)))
+                        .collect::<Result<_, String>>()?,
+                    response: Edits::new(&rule.response)?,
+                })
+            })
+            .collect::<Result<_, String>>()
+            .map(Self)
+    }
+
+    fn matching<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Rewrite> + 'a {
+        self.0
+            .iter()
+            .filter(move |rule| rule.paths.is_empty() || rule.paths.is_match(path))
+    }
+
+    pub fn request(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in self.matching(path) {
+            rule.request.apply(headers);
+        }
+    }
+
+    pub fn params(&self, path: &str, params: &mut ExtraParams) {
+        for rule in self.matching(path) {
+            for (name, value) in &rule.params {
+                params.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    pub fn response(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in self.matching(path) {
+            rule.response.apply(headers);
+        }
+    }
+}
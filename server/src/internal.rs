@@ -0,0 +1,165 @@
+use std::path::{Component, Path, PathBuf};
+
+use http::{HeaderMap, HeaderName, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
+use hyper_staticfile::{AcceptEncoding, ResolveResult, Resolver, ResponseBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::service;
+
+const X_SENDFILE: &str = "x-sendfile";
+const X_ACCEL_REDIRECT: &str = "x-accel-redirect";
+
+/// Maps a URI prefix used in `X-Accel-Redirect` to a directory on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Location {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Directories PHP may hand files from via `X-Sendfile`
+    pub sendfile_roots: Vec<PathBuf>,
+
+    /// Internal locations for `X-Accel-Redirect`
+    pub accel_locations: Vec<Location>,
+
+    /// Maximum number of CGI local redirects followed for a single request, `0`
+    /// sends them to the client as `302` redirects
+    pub max_internal_redirects: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sendfile_roots: Vec::new(),
+            accel_locations: Vec::new(),
+            max_internal_redirects: 10,
+        }
+    }
+}
+
+/// Handles responses where PHP asks the server to produce the actual response.
+pub struct Redirects {
+    options: Options,
+}
+
+impl Redirects {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn max_redirects(&self) -> usize {
+        self.options.max_internal_redirects
+    }
+
+    /// Finds the directory and relative path for a `X-Sendfile` or `X-Accel-Redirect` header.
+    fn resolve(&self, headers: &HeaderMap) -> Option<Result<(&Path, String), String>> {
+        if let Some(value) = headers.get(X_SENDFILE) {
+            let path = Path::new(value.to_str().unwrap_or_default());
+
+            if path.components().any(|c| c == Component::ParentDir) {
+                return Some(Err(path.display().to_string()));
+            }
+
+            return Some(
+                self.options
+                    .sendfile_roots
+                    .iter()
+                    .find_map(|root| {
+                        let rest = path.strip_prefix(root).ok()?;
+                        Some((root.as_path(), rest.to_str()?.to_string()))
+                    })
+                    .ok_or_else(|| path.display().to_string()),
+            );
+        }
+
+        let value = headers.get(X_ACCEL_REDIRECT)?.to_str().unwrap_or_default();
+        let path = value.split_once('?').map_or(value, |(path, _)| path);
+
+        Some(
+            self.options
+                .accel_locations
+                .iter()
+                .find_map(|location| {
+                    let rest = path.strip_prefix(&location.prefix)?;
+                    Some((location.dir.as_path(), rest.to_string()))
+                })
+                .ok_or_else(|| path.to_string()),
+        )
+    }
+
+    /// Serves the file referenced by `X-Sendfile` or `X-Accel-Redirect`, keeping the
+    /// other headers set by PHP. Returns the response untouched if neither is present.
+    pub async fn sendfile(
+        &self,
+        original: &Request<()>,
+        response: Response<BoxBody<Bytes, service::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, service::Error>>, service::Error> {
+        let (root, path) = match self.resolve(response.headers()) {
+            None => return Ok(response),
+            Some(Ok(target)) => target,
+            Some(Err(path)) => {
+                tracing::warn!({ path }, "refusing to send file outside internal locations");
+
+                let mut response = Response::new(BoxBody::default());
+                *response.status_mut() = StatusCode::FORBIDDEN;
+                return Ok(response);
+            }
+        };
+
+        tracing::debug!({ ?root, path }, "sending file for script");
+
+        let resolver = Resolver::new(root);
+        let result = resolver
+            .resolve_path(&path.replace('%', "%25"), AcceptEncoding::none())
+            .await?;
+
+        // Directory redirects would leak the internal location
+        let result = match result {
+            ResolveResult::IsDirectory { .. } => ResolveResult::NotFound,
+            result => result,
+        };
+
+        let mut file = ResponseBuilder::new()
+            .request(original)
+            .build(result)
+            .map_err(std::io::Error::other)?
+            .map(|body| body.map_err(Into::into).boxed());
+
+        let (parts, _) = response.into_parts();
+        let own = file.headers().keys().cloned().collect::<Vec<_>>();
+        let mut previous: Option<HeaderName> = None;
+
+        for (name, value) in parts.headers {
+            let name = match name {
+                Some(name) => {
+                    previous = Some(name.clone());
+                    name
+                }
+                None => match &previous {
+                    Some(name) => name.clone(),
+                    None => continue,
+                },
+            };
+
+            if name.as_str().starts_with("x-accel-")
+                || matches!(
+                    name.as_str(),
+                    X_SENDFILE | "content-length" | "content-encoding" | "transfer-encoding"
+                )
+                || own.contains(&name)
+            {
+                continue;
+            }
+
+            file.headers_mut().append(name, value);
+        }
+
+        Ok(file)
+    }
+}
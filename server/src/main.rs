@@ -1,76 +1,227 @@
-use std::{error::Error, net::SocketAddr, path::PathBuf};
-
-use clap::Parser;
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use admin::Admin;
+use clap::{Parser, Subcommand};
+use config::Config;
 use hyper::server::conn::http1::Builder;
-use hyper_util::rt::{TokioIo, TokioTimer};
-use manager::Manager;
+use hyper_util::{
+    rt::{TokioIo, TokioTimer},
+    server::graceful::GracefulShutdown,
+};
+use metrics::METRICS;
+use reload::Reloader;
 use service::PhpService;
-use tokio::net::TcpListener;
-use tracing_subscriber::EnvFilter;
-
+use supervisor::Supervisor;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tracing_subscriber::{
+    filter::Targets, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+mod access_log;
+mod admin;
+mod assets;
+mod auth;
+mod compress;
+mod config;
+mod cors;
+mod fpm_status;
+mod headers;
+mod internal;
+mod maintenance;
 mod manager;
+mod metrics;
+mod overload;
+mod paths;
+mod ping;
+mod rate_limit;
+mod reload;
 mod request;
+mod request_id;
 mod response;
+mod retry;
 mod service;
+mod supervisor;
+mod telemetry;
+mod upstream;
+mod vhost;
 
 #[derive(Parser)]
 struct Opts {
-    #[clap(long, default_value = "127.0.0.1:9000")]
-    bind: SocketAddr,
+    /// Config file in TOML or YAML format
+    #[clap(long, short, env = "PYPER_CONFIG", global = true)]
+    config: Option<PathBuf>,
 
-    #[clap(long)]
-    ping_path: Option<String>,
+    #[clap(flatten)]
+    overrides: config::Overrides,
 
-    #[clap(long, default_value = "127.0.0.1:3000")]
-    listen: SocketAddr,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
 
-    #[clap(long, default_value = "./")]
-    root_dir: PathBuf,
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the config and exit
+    CheckConfig,
 
-    #[clap(long, default_value = "5")]
-    max_conn: u32,
+    /// Print the effective config after applying the environment and flags
+    PrintConfig {
+        #[clap(long, value_enum, default_value = "toml")]
+        format: config::Format,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let opts = Opts::parse();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+    let config = match Config::load(opts.config.as_deref(), &opts.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    match opts.command {
+        Some(Command::CheckConfig) => {
+            println!("config is valid");
+            return Ok(());
+        }
+        Some(Command::PrintConfig { format }) => {
+            print!("{}", config.print(format)?);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let provider = telemetry::provider(&config.telemetry)?;
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(telemetry::tracer(provider))
+                .with_filter(Targets::new().with_target("server", tracing::Level::INFO))
+        }))
         .init();
 
-    let mut manager = Manager::new(opts.bind);
+    let supervisor =
+        Supervisor::new(config.supervisor.clone(), &config.fastcgi)?.map(Supervisor::start);
+    let service = PhpService::new(reload::state(&config, None).await?);
+    let ready = Arc::new(AtomicBool::new(false));
+
+    if let Some(addr) = config.admin.listen {
+        // Bound here so a taken port fails the startup instead of a background task
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("failed to bind admin listener on {addr}: {e}"))?;
+
+        METRICS.register_pool(service.clone());
 
-    if let Some(path) = opts.ping_path {
-        manager = manager.with_ping(path);
+        let mut admin = Admin::new(service.clone(), Arc::clone(&ready));
+
+        if let Some(token) = config.admin.token.clone() {
+            admin = admin.with_token(token);
+        }
+
+        tokio::spawn(async move {
+            if let Err(e) = admin.serve(listener).await {
+                tracing::error!({ error = ?e }, "admin listener failed");
+            }
+        });
     }
 
-    let pool = bb8::Builder::new()
-        .max_size(opts.max_conn)
-        .build(manager)
-        .await?;
+    tokio::spawn(Reloader::new(service.clone(), opts.config, opts.overrides, config.clone()).run());
 
-    let service = PhpService::new(pool, opts.root_dir);
-    let listener = TcpListener::bind(opts.listen).await?;
+    if let Some(supervisor) = &supervisor {
+        supervisor.wait_ready().await?;
+    }
+
+    let listener = TcpListener::bind(config.listen).await?;
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown(
+        Arc::clone(&ready),
+        Duration::from_secs(config.shutdown_delay),
+    );
+
+    ready.store(true, Ordering::Relaxed);
+    tokio::pin!(shutdown);
 
     loop {
-        let (tcp, _) = listener.accept().await?;
+        let (tcp, remote) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
         let io = TokioIo::new(tcp);
-        let service = service.clone();
+        let service = service.for_connection(remote);
+
+        tracing::trace!({ %remote }, "incoming connection");
 
-        tracing::trace!("incoming connection");
+        let conn = Builder::new()
+            .timer(TokioTimer::new())
+            .half_close(true)
+            .serve_connection(io, service);
+        let conn = graceful.watch(conn);
 
         tokio::task::spawn(async move {
-            if let Err(e) = Builder::new()
-                .timer(TokioTimer::new())
-                .half_close(true)
-                .serve_connection(io, service)
-                .await
-            {
+            if let Err(e) = conn.await {
                 tracing::warn!({ error = ?e }, "failed to serve connection");
             }
 
             tracing::trace!("finished connection");
         });
     }
+
+    drop(listener);
+    tracing::info!("waiting for connections to finish");
+
+    tokio::select! {
+        _ = graceful.shutdown() => tracing::info!("all connections finished"),
+        _ = tokio::time::sleep(Duration::from_secs(config.shutdown_timeout)) => {
+            tracing::warn!("timed out waiting for connections to finish");
+        }
+    }
+
+    if let Some(supervisor) = supervisor {
+        supervisor.stop().await;
+    }
+
+    if let Some(access_log) = service.state().access_log() {
+        access_log.flush().await;
+    }
+
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!({ error = ?e }, "failed to flush spans");
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits for SIGTERM or SIGINT, then fails readiness and keeps serving for `delay`
+/// so the endpoint is removed before connections are drained.
+async fn shutdown(ready: Arc<AtomicBool>, delay: Duration) {
+    let mut term = signal(SignalKind::terminate()).expect("failed to install signal handler");
+    let mut int = signal(SignalKind::interrupt()).expect("failed to install signal handler");
+
+    tokio::select! {
+        _ = term.recv() => {},
+        _ = int.recv() => {},
+    }
+
+    tracing::info!({ ?delay }, "shutdown requested, failing readiness");
+    ready.store(false, Ordering::Relaxed);
+    tokio::time::sleep(delay).await;
 }
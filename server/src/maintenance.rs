@@ -0,0 +1,166 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER},
+    HeaderValue, Request, Response, StatusCode,
+};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::rate_limit::client_ip;
+
+/// How long the existence of the flag file is cached.
+const FLAG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_PAGE: &str = "Down for maintenance, please try again later.\n";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// File enabling maintenance mode while it exists, relative to `root_dir`
+    pub flag_file: Option<PathBuf>,
+
+    /// HTML page served during maintenance, a short text when unset
+    pub page: Option<PathBuf>,
+
+    /// Seconds clients are told to wait before retrying
+    pub retry_after: u64,
+
+    /// Clients still served by the application, e.g. the office network
+    pub bypass_ips: Vec<IpNet>,
+
+    /// Proxies whose `X-Forwarded-For` is trusted to find the client IP
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Headers letting a request through when they have the given value
+    pub bypass_headers: BTreeMap<String, String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            flag_file: None,
+            page: None,
+            retry_after: 300,
+            bypass_ips: Vec::new(),
+            trusted_proxies: Vec::new(),
+            bypass_headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Answers every request with a 503 page while the flag file exists or the admin
+/// API enabled it.
+pub struct Maintenance {
+    flag_file: Option<PathBuf>,
+    /// When the flag file was last checked and whether it existed
+    flag: Mutex<Option<(Instant, bool)>>,
+    enabled: AtomicBool,
+    page: Bytes,
+    html: bool,
+    retry_after: u64,
+    bypass_ips: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    bypass_headers: BTreeMap<String, String>,
+}
+
+impl Maintenance {
+    pub fn new(options: Options, root: &Path) -> Self {
+        Self {
+            flag_file: options.flag_file.map(|file| root.join(file)),
+            flag: Mutex::new(None),
+            enabled: AtomicBool::new(false),
+            page: Bytes::from_static(DEFAULT_PAGE.as_bytes()),
+            html: false,
+            retry_after: options.retry_after,
+            bypass_ips: options.bypass_ips,
+            trusted_proxies: options.trusted_proxies,
+            bypass_headers: options.bypass_headers,
+        }
+    }
+
+    /// Serves `page` as HTML instead of the default text.
+    pub fn with_page(mut self, page: Bytes) -> Self {
+        self.page = page;
+        self.html = true;
+        self
+    }
+
+    /// Whether maintenance mode was enabled through the admin API.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            tracing::info!({ enabled }, "maintenance mode toggled");
+        }
+    }
+
+    /// Whether the flag file exists, checked at most once per second.
+    pub fn is_flagged(&self) -> bool {
+        let Some(file) = &self.flag_file else {
+            return false;
+        };
+
+        let mut flag = self.flag.lock().unwrap();
+
+        match *flag {
+            Some((checked, exists)) if checked.elapsed() < FLAG_CHECK_INTERVAL => exists,
+            _ => {
+                let exists = file.exists();
+                *flag = Some((Instant::now(), exists));
+                exists
+            }
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_enabled() || self.is_flagged()
+    }
+
+    fn bypasses<B>(&self, request: &Request<B>) -> bool {
+        let by_header = self.bypass_headers.iter().any(|(name, value)| {
+            request
+                .headers()
+                .get(name.as_str())
+                .is_some_and(|v| v == value.as_str())
+        });
+
+        by_header
+            || client_ip(request, &self.trusted_proxies)
+                .is_some_and(|ip| self.bypass_ips.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// The maintenance page, unless maintenance mode is off or the request bypasses it.
+    pub fn check<B>(&self, request: &Request<B>) -> Option<Response<Full<Bytes>>> {
+        if !self.is_active() || self.bypasses(request) {
+            return None;
+        }
+
+        let content_type = match self.html {
+            true => "text/html; charset=utf-8",
+            false => "text/plain; charset=utf-8",
+        };
+
+        let mut response = Response::new(Full::new(self.page.clone()));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        headers.insert(RETRY_AFTER, self.retry_after.into());
+
+        Some(response)
+    }
+}
@@ -1,18 +1,31 @@
 use std::{
+    collections::BTreeMap,
     io::ErrorKind,
     net::SocketAddr,
+    pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use bb8::ManageConnection;
 use fastcgi_client::{
     conn::KeepAlive, Client, ClientError, ClientResult, Params, Request, Response,
 };
-use httparse::Status;
-use tokio::{io::AsyncRead, net::TcpStream};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    ping::{self, Ping},
+    response,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -20,66 +33,346 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("not responding: {0}")]
     Client(#[from] fastcgi_client::ClientError),
-    #[error("failed to parse ping response: {0}")]
-    Headers(#[from] httparse::Error),
-    #[error("failed to parse ping response: incomplete headers")]
-    PingIncomplete,
-    #[error("ping failed")]
-    Ping,
+    #[error("failed to parse response: {0}")]
+    Response(#[from] response::Error),
+    #[error("ping failed: {0}")]
+    Ping(String),
     #[error("connection closed")]
     Closed,
+    #[error("connection retired: {0}")]
+    Retired(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Address of the FastCGI server
+    pub bind: SocketAddr,
+
+    /// Maximum number of pooled connections
+    pub max_conn: u32,
+
+    /// Script used to validate pooled connections
+    pub ping_path: Option<String>,
+
+    /// Status the ping script must respond with
+    pub ping_status: u16,
+
+    /// Body the ping script must respond with
+    pub ping_body: ping::Body,
+
+    /// FastCGI params added to ping requests, overriding the defaults
+    pub ping_params: BTreeMap<String, String>,
+
+    /// Seconds a connection is not pinged again after a successful ping, `0` pings
+    /// on every checkout
+    pub ping_interval: u64,
+
+    /// Milliseconds to wait for the ping script
+    pub ping_timeout_ms: u64,
+
+    /// Milliseconds to wait for a new connection, keep it below `overload.max_wait_ms`
+    /// so a backend refusing connections is ejected while requests still wait
+    pub connect_timeout_ms: u64,
+
+    /// php-fpm `pm.status_path` read periodically for metrics and load shedding
+    pub status_path: Option<String>,
+
+    /// Seconds between reads of the status page
+    pub status_interval: u64,
+
+    /// Connections opened at startup and kept open while idle
+    pub min_idle: u32,
+
+    /// Seconds after which a connection is closed, `0` keeps it open
+    pub max_lifetime: u64,
+
+    /// Seconds a connection may stay unused before it is closed, `0` keeps it open
+    pub max_idle: u64,
+
+    /// Requests after which a connection is closed, `0` for no limit. Keep it below
+    /// php-fpm's `pm.max_requests` so php-fpm never closes a connection first.
+    pub max_requests: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 9000)),
+            max_conn: 5,
+            ping_path: None,
+            ping_status: 200,
+            ping_body: ping::Body::default(),
+            ping_params: BTreeMap::new(),
+            ping_interval: 0,
+            ping_timeout_ms: 1000,
+            connect_timeout_ms: 1000,
+            status_path: None,
+            status_interval: 10,
+            min_idle: 0,
+            max_lifetime: 30 * 60,
+            max_idle: 10 * 60,
+            max_requests: 0,
+        }
+    }
+}
+
+/// Limits after which connections are closed instead of reused.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retire {
+    pub max_lifetime: Option<Duration>,
+    pub max_idle: Option<Duration>,
+    pub max_requests: Option<u64>,
+}
+
+impl Retire {
+    pub fn new(options: &Options) -> Self {
+        let seconds = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+
+        Self {
+            max_lifetime: seconds(options.max_lifetime),
+            max_idle: seconds(options.max_idle),
+            max_requests: (options.max_requests > 0).then_some(options.max_requests),
+        }
+    }
+
+    /// Why a connection must not be reused, if it must not.
+    fn reason(&self, stats: &ConnStats) -> Option<&'static str> {
+        if self.max_lifetime.is_some_and(|max| stats.age() >= max) {
+            return Some("max_lifetime");
+        }
+
+        if self.max_idle.is_some_and(|max| stats.idle() >= max) {
+            return Some("max_idle");
+        }
+
+        if self.max_requests.is_some_and(|max| stats.requests() >= max) {
+            return Some("max_requests");
+        }
+
+        None
+    }
+}
+
+/// Age and usage of an open connection.
+pub struct ConnStats {
+    id: u64,
+    created: Instant,
+    requests: AtomicU64,
+    /// Milliseconds after `created` the last request finished
+    last_used: AtomicU64,
+}
+
+impl ConnStats {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn idle(&self) -> Duration {
+        let last_used = Duration::from_millis(self.last_used.load(Ordering::Relaxed));
+        self.age().saturating_sub(last_used)
+    }
+
+    fn used(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.last_used.store(elapsed, Ordering::Relaxed);
+    }
 }
 
+type Registry = Arc<Mutex<BTreeMap<u64, Arc<ConnStats>>>>;
+
 #[derive(Clone)]
 pub struct Manager {
     addr: SocketAddr,
-    ping_path: Option<Arc<String>>,
+    connect_timeout: Option<Duration>,
+    server: Option<SocketAddr>,
+    ping: Option<Arc<Ping>>,
+    retire: Retire,
+    next_id: Arc<AtomicU64>,
+    conns: Registry,
 }
 
 impl Manager {
     pub fn new(addr: SocketAddr) -> Self {
         Self {
             addr,
-            ping_path: None,
+            connect_timeout: None,
+            server: None,
+            ping: None,
+            retire: Retire::default(),
+            next_id: Arc::new(AtomicU64::new(0)),
+            conns: Registry::default(),
         }
     }
 
-    pub fn with_ping(mut self, path: String) -> Self {
-        self.ping_path = Some(Arc::new(path));
+    pub fn with_ping(mut self, ping: Ping) -> Self {
+        self.ping = Some(Arc::new(ping));
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Address pyper listens on, passed to scripts it requests itself.
+    pub fn with_server(mut self, server: SocketAddr) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    pub fn with_retire(mut self, retire: Retire) -> Self {
+        self.retire = retire;
         self
     }
 
+    pub fn retire(&self) -> Retire {
+        self.retire
+    }
+
+    /// Every open connection, including ones of pools replaced by a resize that
+    /// are still in use.
+    pub fn connections(&self) -> Vec<Arc<ConnStats>> {
+        self.conns.lock().unwrap().values().cloned().collect()
+    }
+
     async fn _connect(&self) -> Result<Conn, Error> {
-        let stream = TcpStream::connect(&self.addr).await?;
-        let client = Client::new_keep_alive(stream);
-        Ok(Conn::new(client, self.ping_path.as_ref().map(Arc::clone)))
+        let connect = TcpStream::connect(&self.addr);
+        let stream = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "connect timed out"))??,
+            None => connect.await?,
+        };
+        let stats = Arc::new(ConnStats {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            created: Instant::now(),
+            requests: AtomicU64::new(0),
+            last_used: AtomicU64::new(0),
+        });
+        self.conns
+            .lock()
+            .unwrap()
+            .insert(stats.id, Arc::clone(&stats));
+
+        Ok(Conn::new(
+            stream,
+            self.server,
+            self.ping.as_ref().map(Arc::clone),
+            stats,
+            Arc::clone(&self.conns),
+        ))
+    }
+}
+
+/// Counts the bytes read from a stream.
+struct Tracked {
+    stream: TcpStream,
+    read: Arc<AtomicU64>,
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            let read = (buf.filled().len() - before) as u64;
+            self.read.fetch_add(read, Ordering::Relaxed);
+        }
+
+        poll
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
 
 pub struct Conn {
-    client: Client<TcpStream, KeepAlive>,
-    ping_path: Option<Arc<String>>,
+    client: Client<Tracked, KeepAlive>,
+    read: Arc<AtomicU64>,
+    server: Option<SocketAddr>,
+    ping: Option<Arc<Ping>>,
+    /// Last successful ping
+    validated: Option<Instant>,
     closed: AtomicBool,
+    stats: Arc<ConnStats>,
+    registry: Registry,
 }
 
 impl Conn {
-    pub fn new(client: Client<TcpStream, KeepAlive>, ping_path: Option<Arc<String>>) -> Self {
+    fn new(
+        stream: TcpStream,
+        server: Option<SocketAddr>,
+        ping: Option<Arc<Ping>>,
+        stats: Arc<ConnStats>,
+        registry: Registry,
+    ) -> Self {
+        let read = Arc::new(AtomicU64::new(0));
+        let stream = Tracked {
+            stream,
+            read: Arc::clone(&read),
+        };
+
         Self {
-            client,
-            ping_path,
+            client: Client::new_keep_alive(stream),
+            read,
+            server,
+            ping,
+            validated: None,
             closed: AtomicBool::new(false),
+            stats,
+            registry,
         }
     }
 
+    /// Whether FastCGI sent anything back for the last request.
+    pub fn received(&self) -> bool {
+        self.read.load(Ordering::Relaxed) > 0
+    }
+
     pub async fn send<I: AsyncRead + Unpin>(
         &mut self,
         request: Request<'_, I>,
     ) -> ClientResult<Response> {
-        match self.client.execute(request).await {
+        self.read.store(0, Ordering::Relaxed);
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+
+        let result = self.client.execute(request).await;
+        self.stats.used();
+
+        match result {
             Ok(response) => Ok(response),
             Err(e) => {
                 if let ClientError::Io(e) = &e {
-                    if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof) {
+                    if is_closed(e.kind()) {
                         self.closed.store(true, Ordering::Relaxed);
                     }
                 }
@@ -88,15 +381,94 @@ impl Conn {
             }
         }
     }
+
+    /// Sends a GET request for `path` with `params` added to the defaults.
+    pub async fn get(
+        &mut self,
+        path: &str,
+        query: &str,
+        params: &BTreeMap<String, String>,
+    ) -> Result<Reply, Error> {
+        let mut request_params = script_params(path, self.server).query_string(query);
+        for (name, value) in params {
+            request_params = request_params.custom(name.as_str(), value.as_str());
+        }
+
+        let mut empty = tokio::io::empty();
+        let request = Request::new(request_params, &mut empty);
+        let response = self.send(request).await?;
+        let cgi = response::parse(response.stdout.unwrap_or_default())?;
+
+        // CGI scripts answer 200 unless they send a `Status` header
+        let status = cgi.status.map_or(200, |(status, _)| status.as_u16());
+
+        Ok(Reply {
+            status,
+            body: cgi.body,
+        })
+    }
+
+    /// Requests the ping script, if configured, and checks its response.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        let Some(ping) = self.ping.clone() else {
+            return Ok(());
+        };
+
+        let reply =
+            match tokio::time::timeout(ping.timeout(), self.get(ping.path(), "", ping.params()))
+                .await
+            {
+                Ok(reply) => reply?,
+                Err(_) => {
+                    // The response may still arrive and would be read by the next request
+                    self.closed.store(true, Ordering::Relaxed);
+                    return Err(Error::Ping("timed out".into()));
+                }
+            };
+
+        ping.check(&reply).map_err(Error::Ping)?;
+        self.validated = Some(Instant::now());
+
+        Ok(())
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.registry.lock().unwrap().remove(&self.stats.id);
+    }
+}
+
+/// Response of a script requested by pyper itself.
+pub struct Reply {
+    pub status: u16,
+    pub body: Bytes,
+}
+
+/// Errors meaning the other side closed the connection.
+pub fn is_closed(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    )
 }
 
-fn ping_params(path: &str) -> Params {
-    Params::default()
+fn script_params(path: &str, server: Option<SocketAddr>) -> Params<'_> {
+    let params = Params::default()
         .request_method("GET")
-        .server_name("localhost")
-        .server_port("8000")
         .script_name(path)
-        .script_filename(path)
+        .script_filename(path);
+
+    match server {
+        Some(server) => params
+            .server_name(server.ip().to_string())
+            .server_addr(server.ip().to_string())
+            .server_port(server.port().to_string()),
+        None => params,
+    }
 }
 
 #[async_trait::async_trait]
@@ -113,28 +485,120 @@ impl ManageConnection for Manager {
             return Err(Error::Closed);
         }
 
-        if let Some(path) = conn.ping_path.clone() {
-            let mut empty = tokio::io::empty();
-            let request = Request::new(ping_params(&path), &mut empty);
-            let response = conn.send(request).await?;
-            let stdout = response.stdout.unwrap_or_default();
-            let mut headers = [httparse::EMPTY_HEADER; 64];
-
-            match httparse::parse_headers(&stdout, &mut headers)? {
-                Status::Complete((offset, _)) => {
-                    if &stdout[offset..] != b"pong" {
-                        tracing::error!("ping failed");
-                        return Err(Error::Ping);
-                    }
-                }
-                _ => return Err(Error::PingIncomplete),
-            }
+        if let Some(reason) = self.retire.reason(&conn.stats) {
+            tracing::debug!({ backend = %self.addr, reason }, "retiring connection");
+            return Err(Error::Retired(reason));
         }
 
-        Ok(())
+        let recent = conn
+            .validated
+            .zip(self.ping.as_ref().and_then(|ping| ping.interval()))
+            .is_some_and(|(validated, interval)| validated.elapsed() < interval);
+
+        if recent {
+            return Ok(());
+        }
+
+        conn.ping().await.inspect_err(|e| {
+            tracing::warn!({ backend = %self.addr, reason = %e }, "connection failed validation");
+        })
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.closed.load(Ordering::Relaxed)
+        if conn.closed.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        // Idle time only counts once the connection is back in the pool
+        let retire = Retire {
+            max_idle: None,
+            ..self.retire
+        };
+
+        retire
+            .reason(&conn.stats)
+            .inspect(|reason| {
+                tracing::debug!({ backend = %self.addr, reason }, "retiring connection");
+            })
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Answers one FastCGI request with `stdout`, `None` once the client hung up.
+    pub(crate) async fn respond(stream: &mut TcpStream, stdout: &[u8]) -> Option<()> {
+        loop {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).await.ok()?;
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut content = vec![0; length + header[6] as usize];
+            stream.read_exact(&mut content).await.ok()?;
+
+            // An empty stdin record ends the request
+            if header[1] == 5 && length == 0 {
+                break;
+            }
+        }
+
+        let mut reply = vec![1, 6, 0, 1];
+        reply.extend_from_slice(&(stdout.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0]);
+        reply.extend_from_slice(stdout);
+        reply.extend_from_slice(&[1, 3, 0, 1, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        stream.write_all(&reply).await.ok()
+    }
+
+    #[tokio::test]
+    async fn parses_script_reply() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let manager = Manager::new(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            respond(
+                &mut stream,
+                b"Status: 404 Not Found\nContent-Type: text/plain\n\nmissing",
+            )
+            .await;
+        });
+
+        let mut conn = manager.connect().await.unwrap();
+        let reply = conn.get("/status", "", &BTreeMap::new()).await.unwrap();
+
+        assert_eq!(reply.status, 404);
+        assert_eq!(reply.body, "missing");
+    }
+
+    #[tokio::test]
+    async fn fails_validation_with_ping_reason() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ping = Ping::new(&Options {
+            ping_path: Some("/ping".into()),
+            ..Options::default()
+        })
+        .unwrap();
+        let manager = Manager::new(listener.local_addr().unwrap()).with_ping(ping);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            respond(&mut stream, b"Status: 503\n\nbusy").await;
+        });
+
+        let mut conn = manager.connect().await.unwrap();
+        let error = manager.is_valid(&mut conn).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "ping failed: status 503, expected 200");
+    }
+
+    #[test]
+    fn passes_listen_address_to_scripts() {
+        let params = script_params("/status", Some(([192, 0, 2, 1], 8080).into()));
+
+        assert_eq!(params["SERVER_NAME"], "192.0.2.1");
+        assert_eq!(params["SERVER_ADDR"], "192.0.2.1");
+        assert_eq!(params["SERVER_PORT"], "8080");
     }
 }
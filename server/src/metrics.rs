@@ -0,0 +1,412 @@
+use std::sync::LazyLock;
+
+use fastcgi_client::ClientError;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    CounterVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+
+use crate::service::PhpService;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub duration: HistogramVec,
+    pub bytes_in: IntCounterVec,
+    pub bytes_out: IntCounterVec,
+    pub checkout: Histogram,
+    pub fastcgi_errors: IntCounterVec,
+    pub stderr_lines: IntCounter,
+    pub ejections: IntCounterVec,
+    pub retries: IntCounterVec,
+    pub shed: IntCounterVec,
+    pub breaker_open: IntGaugeVec,
+    pub rate_limited: IntCounter,
+    pub unauthorized: IntCounter,
+    pub file_cache: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("pyper".into()), None).expect("valid registry");
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &["route", "status"],
+            )
+            .unwrap(),
+            duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time until the response headers were ready",
+                ),
+                &["route", "status"],
+            )
+            .unwrap(),
+            bytes_in: IntCounterVec::new(
+                Opts::new("http_request_bytes_total", "Request body bytes received"),
+                &["route"],
+            )
+            .unwrap(),
+            bytes_out: IntCounterVec::new(
+                Opts::new("http_response_bytes_total", "Response body bytes sent"),
+                &["route"],
+            )
+            .unwrap(),
+            checkout: Histogram::with_opts(HistogramOpts::new(
+                "pool_checkout_duration_seconds",
+                "Time spent waiting for a FastCGI connection",
+            ))
+            .unwrap(),
+            fastcgi_errors: IntCounterVec::new(
+                Opts::new("fastcgi_errors_total", "FastCGI client errors"),
+                &["kind"],
+            )
+            .unwrap(),
+            stderr_lines: IntCounter::new("php_stderr_lines_total", "Lines written to stderr")
+                .unwrap(),
+            ejections: IntCounterVec::new(
+                Opts::new(
+                    "upstream_ejections_total",
+                    "Backends ejected after consecutive errors",
+                ),
+                &["upstream", "backend"],
+            )
+            .unwrap(),
+            retries: IntCounterVec::new(
+                Opts::new(
+                    "fastcgi_retries_total",
+                    "Retries of failed FastCGI requests",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            shed: IntCounterVec::new(
+                Opts::new(
+                    "requests_shed_total",
+                    "Requests rejected before reaching FastCGI",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            breaker_open: IntGaugeVec::new(
+                Opts::new(
+                    "circuit_breaker_open",
+                    "1 while the circuit breaker in front of an upstream is open, absent otherwise",
+                ),
+                &["upstream"],
+            )
+            .unwrap(),
+            rate_limited: IntCounter::new(
+                "requests_rate_limited_total",
+                "Requests rejected by a rate limit",
+            )
+            .unwrap(),
+            unauthorized: IntCounter::new(
+                "requests_unauthorized_total",
+                "Requests rejected for missing or wrong credentials",
+            )
+            .unwrap(),
+            file_cache: IntCounterVec::new(
+                Opts::new("file_cache_lookups_total", "Static file cache lookups"),
+                &["outcome"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 14] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.duration.clone()),
+            Box::new(metrics.bytes_in.clone()),
+            Box::new(metrics.bytes_out.clone()),
+            Box::new(metrics.checkout.clone()),
+            Box::new(metrics.fastcgi_errors.clone()),
+            Box::new(metrics.stderr_lines.clone()),
+            Box::new(metrics.ejections.clone()),
+            Box::new(metrics.retries.clone()),
+            Box::new(metrics.shed.clone()),
+            Box::new(metrics.breaker_open.clone()),
+            Box::new(metrics.rate_limited.clone()),
+            Box::new(metrics.unauthorized.clone()),
+            Box::new(metrics.file_cache.clone()),
+        ];
+
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    /// Registers metrics for the backend pools of `service`, following rebuilds on reload.
+    pub fn register_pool(&self, service: PhpService) {
+        if let Err(e) = self
+            .registry
+            .register(Box::new(PoolCollector::new(service)))
+        {
+            tracing::warn!({ error = ?e }, "failed to register pool metrics");
+        }
+    }
+
+    pub fn fastcgi_error(&self, error: &ClientError) {
+        let kind = match error {
+            ClientError::Io(_) => "io",
+            ClientError::RequestIdNotFound { .. } => "request_id_not_found",
+            ClientError::ResponseNotFound { .. } => "response_not_found",
+            ClientError::UnknownRequestType { .. } => "unknown_request_type",
+            ClientError::EndRequestCantMpxConn { .. } => "cant_mpx_conn",
+            ClientError::EndRequestOverloaded { .. } => "overloaded",
+            ClientError::EndRequestUnknownRole { .. } => "unknown_role",
+        };
+
+        self.fastcgi_errors.with_label_values(&[kind]).inc();
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buf = String::new();
+
+        if let Err(e) = TextEncoder::new().encode_utf8(&self.registry.gather(), &mut buf) {
+            tracing::error!({ error = ?e }, "failed to encode metrics");
+        }
+
+        buf
+    }
+}
+
+/// Counts the data bytes passing through a body.
+pub fn count<E: 'static>(body: BoxBody<Bytes, E>, counter: IntCounter) -> BoxBody<Bytes, E> {
+    body.map_frame(move |frame| {
+        if let Some(data) = frame.data_ref() {
+            counter.inc_by(data.len() as u64);
+        }
+
+        frame
+    })
+    .boxed()
+}
+
+/// Reads the pool state and statistics of every backend from bb8 on every scrape.
+struct PoolCollector {
+    service: PhpService,
+    connections: IntGaugeVec,
+    idle: IntGaugeVec,
+    outstanding: IntGaugeVec,
+    ejected: IntGaugeVec,
+    checkouts: IntCounterVec,
+    wait: CounterVec,
+    closed: IntCounterVec,
+    fpm_processes: IntGaugeVec,
+    fpm_listen_queue: IntGaugeVec,
+    fpm_listen_queue_len: IntGaugeVec,
+    fpm_max_listen_queue: IntGaugeVec,
+    fpm_accepted: IntCounterVec,
+    fpm_max_children_reached: IntCounterVec,
+    fpm_slow_requests: IntCounterVec,
+}
+
+impl PoolCollector {
+    fn new(service: PhpService) -> Self {
+        let gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(Opts::new(name, help), &["upstream", "backend"]).unwrap()
+        };
+        let counter = |name: &str, help: &str| {
+            IntCounterVec::new(Opts::new(name, help), &["upstream", "backend"]).unwrap()
+        };
+
+        Self {
+            service,
+            connections: gauge("pool_connections", "Open FastCGI connections"),
+            idle: gauge("pool_idle_connections", "Idle FastCGI connections"),
+            outstanding: gauge("upstream_outstanding_requests", "Requests in flight"),
+            ejected: gauge("upstream_ejected", "Whether the backend is ejected"),
+            checkouts: IntCounterVec::new(
+                Opts::new("pool_checkouts_total", "Connection checkouts by outcome"),
+                &["upstream", "backend", "outcome"],
+            )
+            .unwrap(),
+            wait: CounterVec::new(
+                Opts::new(
+                    "pool_wait_seconds_total",
+                    "Total time spent waiting for connections",
+                ),
+                &["upstream", "backend"],
+            )
+            .unwrap(),
+            closed: IntCounterVec::new(
+                Opts::new(
+                    "pool_connections_closed_total",
+                    "Connections closed by reason",
+                ),
+                &["upstream", "backend", "reason"],
+            )
+            .unwrap(),
+            fpm_processes: IntGaugeVec::new(
+                Opts::new("php_fpm_processes", "php-fpm processes by state"),
+                &["upstream", "backend", "state"],
+            )
+            .unwrap(),
+            fpm_listen_queue: gauge(
+                "php_fpm_listen_queue",
+                "Connections waiting for a php-fpm process",
+            ),
+            fpm_listen_queue_len: gauge("php_fpm_listen_queue_len", "Size of the listen queue"),
+            fpm_max_listen_queue: gauge(
+                "php_fpm_max_listen_queue",
+                "Longest listen queue since php-fpm started",
+            ),
+            fpm_accepted: counter(
+                "php_fpm_accepted_connections_total",
+                "Connections accepted by php-fpm",
+            ),
+            fpm_max_children_reached: counter(
+                "php_fpm_max_children_reached_total",
+                "Times php-fpm hit pm.max_children",
+            ),
+            fpm_slow_requests: counter(
+                "php_fpm_slow_requests_total",
+                "Requests slower than request_slowlog_timeout",
+            ),
+        }
+    }
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.connections.desc(),
+            self.idle.desc(),
+            self.outstanding.desc(),
+            self.ejected.desc(),
+            self.checkouts.desc(),
+            self.wait.desc(),
+            self.closed.desc(),
+            self.fpm_processes.desc(),
+            self.fpm_listen_queue.desc(),
+            self.fpm_listen_queue_len.desc(),
+            self.fpm_max_listen_queue.desc(),
+            self.fpm_accepted.desc(),
+            self.fpm_max_children_reached.desc(),
+            self.fpm_slow_requests.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Reset so backends removed by a reload disappear
+        self.connections.reset();
+        self.idle.reset();
+        self.outstanding.reset();
+        self.ejected.reset();
+        self.checkouts.reset();
+        self.wait.reset();
+        self.closed.reset();
+        self.fpm_processes.reset();
+        self.fpm_listen_queue.reset();
+        self.fpm_listen_queue_len.reset();
+        self.fpm_max_listen_queue.reset();
+        self.fpm_accepted.reset();
+        self.fpm_max_children_reached.reset();
+        self.fpm_slow_requests.reset();
+
+        let upstreams = self.service.upstreams();
+
+        for backend in upstreams.iter().flat_map(|upstream| upstream.backends()) {
+            let addr = backend.addr().to_string();
+            let labels = [backend.upstream(), &addr];
+            let state = backend.pool().state();
+            let stats = backend.statistics();
+
+            self.connections
+                .with_label_values(&labels)
+                .set(state.connections.into());
+            self.idle
+                .with_label_values(&labels)
+                .set(state.idle_connections.into());
+            self.outstanding
+                .with_label_values(&labels)
+                .set(backend.outstanding() as i64);
+            self.ejected
+                .with_label_values(&labels)
+                .set(backend.is_ejected().into());
+
+            for (outcome, value) in [
+                ("direct", stats.get_direct),
+                ("waited", stats.get_waited),
+                ("timed_out", stats.get_timed_out),
+            ] {
+                self.checkouts
+                    .with_label_values(&[backend.upstream(), &addr, outcome])
+                    .inc_by(value);
+            }
+
+            self.wait
+                .with_label_values(&labels)
+                .inc_by(stats.get_wait_time.as_secs_f64());
+
+            for (reason, value) in [
+                ("broken", stats.connections_closed_broken),
+                ("invalid", stats.connections_closed_invalid),
+                ("max_lifetime", stats.connections_closed_max_lifetime),
+                ("idle_timeout", stats.connections_closed_idle_timeout),
+            ] {
+                self.closed
+                    .with_label_values(&[backend.upstream(), &addr, reason])
+                    .inc_by(value);
+            }
+
+            let Some(fpm) = backend.fpm_status() else {
+                continue;
+            };
+
+            for (state, value) in [
+                ("active", fpm.active_processes),
+                ("idle", fpm.idle_processes),
+            ] {
+                self.fpm_processes
+                    .with_label_values(&[backend.upstream(), &addr, state])
+                    .set(value as i64);
+            }
+
+            for (gauge, value) in [
+                (&self.fpm_listen_queue, fpm.listen_queue),
+                (&self.fpm_listen_queue_len, fpm.listen_queue_len),
+                (&self.fpm_max_listen_queue, fpm.max_listen_queue),
+            ] {
+                gauge.with_label_values(&labels).set(value as i64);
+            }
+
+            for (counter, value) in [
+                (&self.fpm_accepted, fpm.accepted_conn),
+                (&self.fpm_max_children_reached, fpm.max_children_reached),
+                (&self.fpm_slow_requests, fpm.slow_requests),
+            ] {
+                counter.with_label_values(&labels).inc_by(value);
+            }
+        }
+
+        [
+            self.connections.collect(),
+            self.idle.collect(),
+            self.outstanding.collect(),
+            self.ejected.collect(),
+            self.checkouts.collect(),
+            self.wait.collect(),
+            self.closed.collect(),
+            self.fpm_processes.collect(),
+            self.fpm_listen_queue.collect(),
+            self.fpm_listen_queue_len.collect(),
+            self.fpm_max_listen_queue.collect(),
+            self.fpm_accepted.collect(),
+            self.fpm_max_children_reached.collect(),
+            self.fpm_slow_requests.collect(),
+        ]
+        .concat()
+    }
+}
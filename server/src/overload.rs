@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{metrics::METRICS, paths::Paths, upstream::Backend};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Requests allowed to wait for a FastCGI connection, further requests get a 503
+    pub queue_depth: usize,
+
+    /// Milliseconds a request may wait for a connection
+    pub max_wait_ms: u64,
+
+    /// Paths served from a separate queue that bypasses the circuit breaker
+    pub priority_paths: Paths,
+
+    /// Requests allowed to wait in the priority queue
+    pub priority_queue_depth: usize,
+
+    /// Connections of each backend only the priority queue may use, applies when
+    /// `priority_paths` is set
+    pub priority_connections: u32,
+
+    /// Consecutive connection or FastCGI errors opening the circuit breaker of an
+    /// upstream
+    pub breaker_failures: u32,
+
+    /// Seconds the circuit breaker stays open before letting a probe request through
+    pub breaker_open: u64,
+
+    /// php-fpm listen queue length from which requests are shed, `0` disables it.
+    /// Needs `fastcgi.status_path`.
+    pub max_listen_queue: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            queue_depth: 128,
+            max_wait_ms: 5000,
+            priority_paths: Paths::default(),
+            priority_queue_depth: 16,
+            priority_connections: 1,
+            breaker_failures: 5,
+            breaker_open: 10,
+            max_listen_queue: 0,
+        }
+    }
+}
+
+/// Why a request was rejected without reaching FastCGI.
+#[derive(Debug, thiserror::Error)]
+#[error("request shed: {reason}")]
+pub struct Shed {
+    pub reason: &'static str,
+    pub retry_after: Duration,
+}
+
+enum Breaker {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe request is in flight, another one is let through after `until`
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Normal,
+    Priority,
+}
+
+/// Connections of a backend the normal lane may use, sized for `max_size`.
+struct Shared {
+    max_size: u32,
+    permits: Arc<Semaphore>,
+}
+
+/// Bounds the requests waiting for a connection and stops sending requests to
+/// an upstream while it keeps failing.
+pub struct Overload {
+    options: Options,
+    waiting: [AtomicUsize; 2],
+    /// Circuit breakers by upstream name
+    breakers: Mutex<HashMap<String, Breaker>>,
+    shared: Mutex<HashMap<(String, SocketAddr), Shared>>,
+}
+
+impl Overload {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            waiting: Default::default(),
+            breakers: Mutex::new(HashMap::new()),
+            shared: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn lane(&self, path: &str) -> Lane {
+        match self.options.priority_paths.is_match(path) {
+            true => Lane::Priority,
+            false => Lane::Normal,
+        }
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.options.max_wait_ms)
+    }
+
+    fn open_for(&self) -> Duration {
+        Duration::from_secs(self.options.breaker_open)
+    }
+
+    fn shed(&self, reason: &'static str, retry_after: Duration) -> Shed {
+        METRICS.shed.with_label_values(&[reason]).inc();
+        Shed {
+            reason,
+            retry_after,
+        }
+    }
+
+    /// Admits a request for `upstream` into the wait queue of its lane, the request
+    /// leaves the queue when the returned ticket is dropped. `listen_queue` is the
+    /// last known php-fpm listen queue of the backend the request goes to.
+    pub fn admit(
+        self: &Arc<Self>,
+        lane: Lane,
+        upstream: &str,
+        listen_queue: Option<u64>,
+    ) -> Result<Ticket, Shed> {
+        if lane == Lane::Normal {
+            let mut breakers = self.breakers.lock().unwrap();
+            let breaker = breakers
+                .entry(upstream.to_string())
+                .or_insert(Breaker::Closed { failures: 0 });
+            let now = Instant::now();
+
+            match *breaker {
+                Breaker::Closed { .. } => {}
+                Breaker::Open { until } | Breaker::HalfOpen { until } if now >= until => {
+                    tracing::info!(
+                        { upstream },
+                        "circuit breaker half-open, sending a probe request"
+                    );
+                    *breaker = Breaker::HalfOpen {
+                        until: now + self.open_for(),
+                    };
+                }
+                Breaker::Open { until } | Breaker::HalfOpen { until } => {
+                    return Err(self.shed("circuit_open", until - now));
+                }
+            }
+
+            let max = self.options.max_listen_queue;
+            if max > 0 && listen_queue.is_some_and(|queue| queue >= max) {
+                return Err(self.shed("listen_queue", Duration::from_secs(1)));
+            }
+        }
+
+        let (waiting, depth) = match lane {
+            Lane::Normal => (&self.waiting[0], self.options.queue_depth),
+            Lane::Priority => (&self.waiting[1], self.options.priority_queue_depth),
+        };
+
+        if waiting.fetch_add(1, Ordering::Relaxed) >= depth {
+            waiting.fetch_sub(1, Ordering::Relaxed);
+            return Err(self.shed("queue_full", Duration::from_secs(1)));
+        }
+
+        Ok(Ticket {
+            overload: Arc::clone(self),
+            lane,
+        })
+    }
+
+    /// Waits until a normal lane request may take a connection of `backend`, keeping
+    /// `priority_connections` of them free for the priority lane. The connection
+    /// must be returned before the permit is dropped.
+    pub async fn reserve(&self, lane: Lane, backend: &Backend) -> Option<OwnedSemaphorePermit> {
+        let reserved = self.options.priority_connections;
+
+        if lane == Lane::Priority || reserved == 0 || self.options.priority_paths.is_empty() {
+            return None;
+        }
+
+        let permits = {
+            let mut shared = self.shared.lock().unwrap();
+            let key = (backend.upstream().to_string(), backend.addr());
+            let max_size = backend.max_size();
+            let shared = shared.entry(key).or_insert_with(|| Shared {
+                max_size: 0,
+                permits: Arc::new(Semaphore::new(0)),
+            });
+
+            // Sized again after the pool was resized, in-flight permits go to the old one
+            if shared.max_size != max_size {
+                *shared = Shared {
+                    max_size,
+                    permits: Arc::new(Semaphore::new(
+                        max_size.saturating_sub(reserved).max(1) as usize
+                    )),
+                };
+            }
+
+            Arc::clone(&shared.permits)
+        };
+
+        permits.acquire_owned().await.ok()
+    }
+
+    /// Queue timeouts mean the backend is busy, not failing, so the breaker ignores them.
+    pub fn timed_out(&self) -> Shed {
+        self.shed("queue_timeout", Duration::from_secs(1))
+    }
+
+    pub fn success(&self, upstream: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(upstream) else {
+            return;
+        };
+
+        if !matches!(*breaker, Breaker::Closed { .. }) {
+            tracing::info!({ upstream }, "circuit breaker closed");
+            let _ = METRICS.breaker_open.remove_label_values(&[upstream]);
+        }
+
+        *breaker = Breaker::Closed { failures: 0 };
+    }
+
+    /// Records a connection or FastCGI error of `upstream`.
+    pub fn failure(&self, upstream: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers
+            .entry(upstream.to_string())
+            .or_insert(Breaker::Closed { failures: 0 });
+        let open = Breaker::Open {
+            until: Instant::now() + self.open_for(),
+        };
+
+        match *breaker {
+            Breaker::Closed { failures } if failures + 1 < self.options.breaker_failures.max(1) => {
+                *breaker = Breaker::Closed {
+                    failures: failures + 1,
+                };
+            }
+            Breaker::Closed { failures } => {
+                tracing::warn!({ upstream, failures = failures + 1 }, "circuit breaker opened");
+                METRICS.breaker_open.with_label_values(&[upstream]).set(1);
+                *breaker = open;
+            }
+            Breaker::HalfOpen { .. } => {
+                tracing::warn!(
+                    { upstream },
+                    "probe request failed, circuit breaker opened again"
+                );
+                *breaker = open;
+            }
+            Breaker::Open { .. } => {}
+        }
+    }
+}
+
+pub struct Ticket {
+    overload: Arc<Overload>,
+    lane: Lane,
+}
+
+impl Ticket {
+    pub fn lane(&self) -> Lane {
+        self.lane
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let index = match self.lane {
+            Lane::Normal => 0,
+            Lane::Priority => 1,
+        };
+
+        self.overload.waiting[index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Overload {
+    /// Takes the breakers of a replaced config off the gauge, other instances keep
+    /// theirs.
+    fn drop(&mut self) {
+        for (upstream, breaker) in self.breakers.get_mut().unwrap().iter() {
+            if !matches!(breaker, Breaker::Closed { .. }) {
+                let _ = METRICS.breaker_open.remove_label_values(&[upstream]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manager, upstream::Upstream};
+
+    fn overload(options: Options) -> Arc<Overload> {
+        Arc::new(Overload::new(Options {
+            breaker_failures: 2,
+            ..options
+        }))
+    }
+
+    #[test]
+    fn opens_breaker_per_upstream() {
+        let overload = overload(Options::default());
+
+        overload.failure("a");
+        assert!(overload.admit(Lane::Normal, "a", None).is_ok());
+        overload.failure("a");
+
+        let shed = overload.admit(Lane::Normal, "a", None).err().unwrap();
+        assert_eq!(shed.reason, "circuit_open");
+        assert!(overload.admit(Lane::Normal, "b", None).is_ok());
+        assert!(overload.admit(Lane::Priority, "a", None).is_ok());
+    }
+
+    /// Value of the breaker gauge of `upstream`, `None` when it isn't exported.
+    fn breaker_gauge(upstream: &str) -> Option<i64> {
+        use prometheus::core::Collector;
+
+        METRICS.breaker_open.collect()[0]
+            .get_metric()
+            .iter()
+            .find(|metric| metric.get_label()[0].value() == upstream)
+            .map(|metric| metric.get_gauge().get_value() as i64)
+    }
+
+    #[test]
+    fn keeps_gauge_of_other_instances() {
+        let first = overload(Options::default());
+        first.failure("gauge-first");
+        first.failure("gauge-first");
+        assert_eq!(breaker_gauge("gauge-first"), Some(1));
+
+        let second = overload(Options::default());
+        second.failure("gauge-second");
+        second.failure("gauge-second");
+        assert_eq!(breaker_gauge("gauge-first"), Some(1));
+
+        second.success("gauge-second");
+        assert_eq!(breaker_gauge("gauge-second"), None);
+
+        drop(first);
+        assert_eq!(breaker_gauge("gauge-first"), None);
+    }
+
+    #[test]
+    fn ignores_queue_timeouts() {
+        let overload = overload(Options::default());
+
+        for _ in 0..3 {
+            overload.timed_out();
+        }
+        assert!(overload.admit(Lane::Normal, "a", None).is_ok());
+
+        overload.failure("a");
+        overload.success("a");
+        overload.failure("a");
+        assert!(overload.admit(Lane::Normal, "a", None).is_ok());
+    }
+
+    #[test]
+    fn limits_queue_depth() {
+        let overload = overload(Options {
+            queue_depth: 1,
+            ..Options::default()
+        });
+
+        let ticket = overload.admit(Lane::Normal, "a", None).unwrap();
+        let shed = overload.admit(Lane::Normal, "a", None).err().unwrap();
+        assert_eq!(shed.reason, "queue_full");
+
+        drop(ticket);
+        assert!(overload.admit(Lane::Normal, "a", None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reserves_connections_for_priority_lane() {
+        let overload = overload(Options {
+            priority_paths: serde_json::from_str(r#"["/health.php"]"#).unwrap(),
+            priority_connections: 1,
+            ..Options::default()
+        });
+        let fastcgi = manager::Options {
+            max_conn: 3,
+            ..manager::Options::default()
+        };
+        let upstream = Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &fastcgi,
+            &Default::default(),
+        );
+        let backend = &upstream.backends()[0];
+
+        let first = overload.reserve(Lane::Normal, backend).await;
+        let second = overload.reserve(Lane::Normal, backend).await;
+        assert!(first.is_some() && second.is_some());
+
+        let third = overload.reserve(Lane::Normal, backend);
+        assert!(tokio::time::timeout(Duration::from_millis(20), third)
+            .await
+            .is_err());
+        assert!(overload.reserve(Lane::Priority, backend).await.is_none());
+
+        drop(first);
+        assert!(overload.reserve(Lane::Normal, backend).await.is_some());
+    }
+}
@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+/// Request path globs like `/admin/**` or `*.css`, where `*` does not cross `/`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Paths {
+    patterns: Vec<String>,
+    set: GlobSet,
+}
+
+impl Paths {
+    /// Matches the [normalized](normalize) path, so `//admin` or `/%61dmin` can't
+    /// slip past a rule for `/admin`.
+    pub fn is_match(&self, path: &str) -> bool {
+        normalize(path).is_some_and(|path| self.set.is_match(&*path))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = path.bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => decoded.push(hex(bytes.next()?)? << 4 | hex(bytes.next()?)?),
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Percent-decodes a request path and resolves empty, `.` and `..` segments, the
+/// way the file system would see it.
+///
+/// Returns `None` for paths that can't be served: invalid escapes or UTF-8, NUL
+/// bytes and `..` above the root.
+pub fn normalize(path: &str) -> Option<Cow<'_, str>> {
+    if !path.starts_with('/') {
+        return Some(Cow::Borrowed(path));
+    }
+
+    let canonical = !path.contains('%')
+        && !path.contains("//")
+        && path
+            .split('/')
+            .all(|segment| segment != "." && segment != "..");
+
+    if canonical {
+        return Some(Cow::Borrowed(path));
+    }
+
+    let decoded = percent_decode(path)?;
+
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let mut directory = false;
+
+    for segment in decoded.split('/') {
+        directory = matches!(segment, "" | "." | "..");
+
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+
+    if directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    Some(Cow::Owned(normalized))
+}
+
+impl TryFrom<Vec<String>> for Paths {
+    type Error = globset::Error;
+
+    fn try_from(patterns: Vec<String>) -> Result<Self, Self::Error> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in &patterns {
+            builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+        }
+
+        Ok(Self {
+            set: builder.build()?,
+            patterns,
+        })
+    }
+}
+
+impl From<Paths> for Vec<String> {
+    fn from(paths: Paths) -> Self {
+        paths.patterns
+    }
+}
+
+impl PartialEq for Paths {
+    fn eq(&self, other: &Self) -> bool {
+        self.patterns == other.patterns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_canonical_paths() {
+        for path in ["/", "/admin", "/admin/", "/admin/index.php", "*"] {
+            assert!(matches!(normalize(path), Some(Cow::Borrowed(p)) if p == path));
+        }
+    }
+
+    #[test]
+    fn resolves_segments_and_escapes() {
+        let cases = [
+            ("//admin", "/admin"),
+            ("/./admin", "/admin"),
+            ("/public/../admin/", "/admin/"),
+            ("/admin/.", "/admin/"),
+            ("/%61dmin", "/admin"),
+            ("/public%2F..%2Fadmin", "/admin"),
+            ("/caf%C3%A9", "/café"),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(normalize(path).as_deref(), Some(expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn rejects_unservable_paths() {
+        for path in ["/..", "/admin/../..", "/%zz", "/%6", "/%00", "/%ff"] {
+            assert_eq!(normalize(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn matches_normalized_paths() {
+        let paths = Paths::try_from(vec!["/admin/**".to_string()]).unwrap();
+
+        assert!(paths.is_match("//admin/users"));
+        assert!(paths.is_match("/x/../admin/users"));
+        assert!(paths.is_match("/%61dmin/users"));
+        assert!(!paths.is_match("/public/users"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::manager::{self, Reply};
+
+/// Regular expression matched against response bodies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Ok(Self(Regex::new(&pattern)?))
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.0.as_str().to_string()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Body the ping script must answer with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Body {
+    Any,
+    Exact(String),
+    Regex(Pattern),
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Exact("pong".into())
+    }
+}
+
+/// Request validating pooled connections and the response it must get.
+pub struct Ping {
+    path: String,
+    params: BTreeMap<String, String>,
+    status: u16,
+    body: Body,
+    interval: Option<Duration>,
+    timeout: Duration,
+}
+
+impl Ping {
+    /// Returns `None` when no ping script is configured.
+    pub fn new(options: &manager::Options) -> Option<Self> {
+        let path = options.ping_path.clone()?;
+
+        Some(Self {
+            path,
+            params: options.ping_params.clone(),
+            status: options.ping_status,
+            body: options.ping_body.clone(),
+            interval: (options.ping_interval > 0)
+                .then(|| Duration::from_secs(options.ping_interval)),
+            timeout: Duration::from_millis(options.ping_timeout_ms),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+
+    /// Time a connection stays valid after a successful ping, pinged on every
+    /// checkout when `None`.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Checks the response of the ping script, returning why it failed.
+    pub fn check(&self, reply: &Reply) -> Result<(), String> {
+        if reply.status != self.status {
+            return Err(format!("status {}, expected {}", reply.status, self.status));
+        }
+
+        let matches = match &self.body {
+            Body::Any => true,
+            Body::Exact(expected) => reply.body == expected.as_bytes(),
+            Body::Regex(pattern) => pattern.0.is_match(&reply.body),
+        };
+
+        match matches {
+            true => Ok(()),
+            false => Err(format!(
+                "unexpected body {:?}",
+                String::from_utf8_lossy(&reply.body[..reply.body.len().min(100)])
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(body: Body) -> Ping {
+        Ping::new(&manager::Options {
+            ping_path: Some("/ping".into()),
+            ping_body: body,
+            ..manager::Options::default()
+        })
+        .unwrap()
+    }
+
+    fn reply(status: u16, body: &'static str) -> Reply {
+        Reply {
+            status,
+            body: body.into(),
+        }
+    }
+
+    #[test]
+    fn rejects_other_status() {
+        let reason = ping(Body::Any).check(&reply(500, "pong")).unwrap_err();
+
+        assert_eq!(reason, "status 500, expected 200");
+        assert_eq!(
+            manager::Error::Ping(reason).to_string(),
+            "ping failed: status 500, expected 200"
+        );
+    }
+
+    #[test]
+    fn compares_exact_body() {
+        let ping = ping(Body::default());
+
+        assert!(ping.check(&reply(200, "pong")).is_ok());
+        assert_eq!(
+            ping.check(&reply(200, "pong\n")).unwrap_err(),
+            r#"unexpected body "pong\n""#
+        );
+    }
+
+    #[test]
+    fn matches_body_pattern() {
+        let pattern = Pattern::try_from(r"^ok( \d+)?$".to_string()).unwrap();
+        let ping = ping(Body::Regex(pattern));
+
+        assert!(ping.check(&reply(200, "ok 42")).is_ok());
+        assert_eq!(
+            ping.check(&reply(200, "error")).unwrap_err(),
+            r#"unexpected body "error""#
+        );
+    }
+
+    #[test]
+    fn shortens_body_in_reason() {
+        let body = "x".repeat(150).leak();
+        let reason = ping(Body::default()).check(&reply(200, body)).unwrap_err();
+
+        assert_eq!(reason, format!("unexpected body {:?}", "x".repeat(100)));
+    }
+}
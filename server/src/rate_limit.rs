@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Instant,
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue, Request};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::METRICS, paths::Paths};
+
+/// Buckets kept per rule, making room evicts full or else idle buckets.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Buckets looked at when making room for a new one.
+const EVICT_BATCH: usize = 64;
+
+const FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// What requests are grouped into buckets by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Key {
+    #[default]
+    ClientIp,
+    /// Requests without the header are keyed on the client IP
+    Header(String),
+    /// One bucket shared by all requests matching the rule
+    Route,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Paths the rule applies to, all PHP requests when empty
+    #[serde(default)]
+    pub paths: Paths,
+
+    #[serde(default)]
+    pub key: Key,
+
+    /// Requests per second
+    pub rate: f64,
+
+    /// Requests allowed in a burst
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Networks whose `X-Forwarded-For` header is trusted to carry the client IP
+    pub trusted_proxies: Vec<IpNet>,
+
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again
+    reset: u64,
+}
+
+impl Quota {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(LIMIT, HeaderValue::from(self.limit));
+        headers.insert(REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RESET, HeaderValue::from(self.reset));
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("rate limit exceeded")]
+pub struct Limited {
+    pub quota: Quota,
+    /// Seconds until the next request is allowed
+    pub retry_after: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limiter {
+    rule: Rule,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    fn capacity(&self) -> f64 {
+        f64::from(self.rule.burst)
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rule.rate).min(self.capacity());
+        bucket.updated = now;
+    }
+
+    /// Makes room for a new bucket by looking at up to `EVICT_BATCH` buckets, dropping
+    /// the full ones or else the least recently used of them.
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let capacity = self.capacity();
+        let mut full = Vec::new();
+        let mut stalest: Option<(&String, Instant)> = None;
+
+        for (key, bucket) in buckets.iter().take(EVICT_BATCH) {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+
+            if bucket.tokens + elapsed * self.rule.rate >= capacity {
+                full.push(key.clone());
+            } else if stalest.is_none_or(|(_, updated)| bucket.updated < updated) {
+                stalest = Some((key, bucket.updated));
+            }
+        }
+
+        if full.is_empty() {
+            full.extend(stalest.map(|(key, _)| key.clone()));
+        }
+
+        for key in full {
+            buckets.remove(&key);
+        }
+    }
+
+    fn seconds(&self, tokens: f64) -> u64 {
+        (tokens / self.rule.rate).ceil() as u64
+    }
+
+    fn quota(&self, bucket: &Bucket) -> Quota {
+        Quota {
+            limit: self.rule.burst,
+            remaining: bucket.tokens as u32,
+            reset: self.seconds(self.capacity() - bucket.tokens),
+        }
+    }
+}
+
+/// Walks `X-Forwarded-For` from the right while the addresses belong to trusted proxies.
+pub fn client_ip<B>(request: &Request<B>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let mut ip = request.extensions().get::<SocketAddr>()?.ip();
+
+    let forwarded = request
+        .headers()
+        .get_all(FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+
+    for hop in forwarded.into_iter().rev() {
+        if !trusted_proxies.iter().any(|net| net.contains(&ip)) {
+            break;
+        }
+
+        match hop.trim().parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+
+    Some(ip)
+}
+
+/// Token bucket rate limits for PHP requests.
+pub struct RateLimiter {
+    trusted_proxies: Vec<IpNet>,
+    limiters: Vec<Limiter>,
+}
+
+impl RateLimiter {
+    /// Returns `None` when no rules are configured.
+    pub fn new(options: Options) -> Option<Self> {
+        if options.rules.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            trusted_proxies: options.trusted_proxies,
+            limiters: options
+                .rules
+                .into_iter()
+                .map(|rule| Limiter {
+                    rule,
+                    buckets: Mutex::new(HashMap::new()),
+                })
+                .collect(),
+        })
+    }
+
+    /// Takes a token from every rule matching the request, returning the most
+    /// restrictive quota. No token is taken when any of the rules is exhausted.
+    pub fn check<B>(&self, request: &Request<B>) -> Result<Option<Quota>, Limited> {
+        let now = Instant::now();
+        let path = request.uri().path();
+
+        let matched: Vec<_> = self
+            .limiters
+            .iter()
+            .filter(|limiter| limiter.rule.paths.is_empty() || limiter.rule.paths.is_match(path))
+            .map(|limiter| (limiter, self.key(&limiter.rule, request)))
+            .collect();
+
+        // Locked in rule order for the whole check, so concurrent requests can't
+        // interleave between checking and taking
+        let mut locked: Vec<_> = matched
+            .iter()
+            .map(|(limiter, _)| limiter.buckets.lock().unwrap())
+            .collect();
+
+        let mut buckets = Vec::with_capacity(matched.len());
+        for ((limiter, key), map) in matched.iter().zip(&mut locked) {
+            if map.len() >= MAX_BUCKETS && !map.contains_key(key) {
+                limiter.evict(map, now);
+            }
+
+            let bucket = map.entry(key.clone()).or_insert(Bucket {
+                tokens: limiter.capacity(),
+                updated: now,
+            });
+            limiter.refill(bucket, now);
+            buckets.push((*limiter, bucket));
+        }
+
+        let limited = buckets
+            .iter()
+            .filter(|(_, bucket)| bucket.tokens < 1.0)
+            .map(|(limiter, bucket)| Limited {
+                quota: limiter.quota(bucket),
+                retry_after: limiter.seconds(1.0 - bucket.tokens).max(1),
+            })
+            .max_by_key(|limited| limited.retry_after);
+
+        if let Some(limited) = limited {
+            METRICS.rate_limited.inc();
+            return Err(limited);
+        }
+
+        let mut lowest: Option<Quota> = None;
+
+        for (limiter, bucket) in buckets {
+            bucket.tokens -= 1.0;
+            let quota = limiter.quota(bucket);
+
+            if lowest.is_none_or(|lowest| quota.remaining < lowest.remaining) {
+                lowest = Some(quota);
+            }
+        }
+
+        Ok(lowest)
+    }
+
+    fn key<B>(&self, rule: &Rule, request: &Request<B>) -> String {
+        let header = match &rule.key {
+            Key::Header(name) => request.headers().get(name),
+            _ => None,
+        };
+
+        match (&rule.key, header) {
+            (Key::Route, _) => String::new(),
+            (_, Some(value)) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            _ => client_ip(request, &self.trusted_proxies)
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn rule(paths: &str, burst: u32) -> Rule {
+        Rule {
+            paths: serde_json::from_str(paths).unwrap(),
+            key: Key::ClientIp,
+            rate: 1.0,
+            burst,
+        }
+    }
+
+    fn request(path: &str, client: [u8; 4], forwarded: Option<&str>) -> Request<()> {
+        let mut request = Request::get(path);
+        if let Some(forwarded) = forwarded {
+            request = request.header(FORWARDED_FOR, forwarded);
+        }
+        let mut request = request.body(()).unwrap();
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from((client, 4000)));
+        request
+    }
+
+    fn limiter(rules: Vec<Rule>) -> RateLimiter {
+        RateLimiter::new(Options {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            rules,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn limits_after_burst() {
+        let limiter = limiter(vec![rule("[]", 2)]);
+        let client = request("/", [192, 0, 2, 1], None);
+
+        assert_eq!(limiter.check(&client).unwrap().unwrap().remaining, 1);
+        assert_eq!(limiter.check(&client).unwrap().unwrap().remaining, 0);
+
+        let limited = limiter.check(&client).unwrap_err();
+        assert_eq!(limited.retry_after, 1);
+        assert_eq!(limited.quota.remaining, 0);
+
+        // Other clients have their own bucket
+        assert!(limiter.check(&request("/", [192, 0, 2, 2], None)).is_ok());
+    }
+
+    #[test]
+    fn takes_no_token_when_a_rule_is_exhausted() {
+        let limiter = limiter(vec![rule(r#"["/login"]"#, 1), rule("[]", 3)]);
+        let login = request("/login", [192, 0, 2, 1], None);
+
+        assert_eq!(limiter.check(&login).unwrap().unwrap().remaining, 0);
+        assert!(limiter.check(&login).is_err());
+        assert!(limiter.check(&login).is_err());
+
+        let other = request("/other", [192, 0, 2, 1], None);
+        assert_eq!(limiter.check(&other).unwrap().unwrap().remaining, 1);
+    }
+
+    #[test]
+    fn keys_on_forwarded_client_behind_trusted_proxies() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+
+        let proxied = request("/", [10, 0, 0, 1], Some("203.0.113.7, 10.0.0.2"));
+        assert_eq!(client_ip(&proxied, &trusted), Some([203, 0, 113, 7].into()));
+
+        // A client can't claim another address by sending the header itself
+        let direct = request("/", [192, 0, 2, 1], Some("203.0.113.7"));
+        assert_eq!(client_ip(&direct, &trusted), Some([192, 0, 2, 1].into()));
+    }
+
+    #[test]
+    fn evicts_full_buckets_first() {
+        let limiter = Limiter {
+            rule: rule("[]", 2),
+            buckets: Mutex::new(HashMap::new()),
+        };
+        let now = Instant::now();
+        let bucket = |tokens, age| Bucket {
+            tokens,
+            updated: now - Duration::from_millis(age),
+        };
+        let mut buckets = HashMap::from([
+            ("full".to_string(), bucket(2.0, 0)),
+            ("refilled".to_string(), bucket(0.0, 3000)),
+            ("used".to_string(), bucket(0.0, 0)),
+        ]);
+
+        limiter.evict(&mut buckets, now);
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), ["used"]);
+
+        buckets.insert("older".into(), bucket(1.0, 500));
+        limiter.evict(&mut buckets, now);
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), ["used"]);
+    }
+}
@@ -0,0 +1,449 @@
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use serde_json::Value;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    access_log::AccessLog,
+    assets::Assets,
+    auth::{self, Auth},
+    compress::Compressor,
+    config::{self, Config, Overrides},
+    cors::Cors,
+    headers::{Locations, Rewrites},
+    internal::Redirects,
+    maintenance::Maintenance,
+    manager,
+    overload::Overload,
+    rate_limit::RateLimiter,
+    request_id::RequestIds,
+    retry::Retry,
+    service::{PhpService, State},
+    upstream::{self, Upstream},
+    vhost::{Hosts, Site, Unknown},
+};
+
+/// Keys only read at startup, changing them requires a restart.
+const STATIC_KEYS: &[&str] = &[
+    "listen",
+    "admin.",
+    "supervisor.",
+    "shutdown_delay",
+    "shutdown_timeout",
+    "telemetry.",
+];
+
+/// Keys whose values may hold tokens or secrets read from the environment, only
+/// their names are logged.
+const SECRET_KEYS: &[&str] = &["admin.token", "auth.rules", "rewrites"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Config(#[from] config::Error),
+    #[error("failed to open access log: {0}")]
+    AccessLog(#[from] std::io::Error),
+    #[error("failed to load auth rules: {0}")]
+    Auth(#[from] auth::Error),
+    #[error("failed to read maintenance page: {0}")]
+    MaintenancePage(std::io::Error),
+}
+
+/// Builds the service state for `config`, reusing the upstreams and access log of
+/// `previous` when their settings did not change.
+pub async fn state(config: &Config, previous: Option<(&Config, &State)>) -> Result<State, Error> {
+    let mut upstreams = previous
+        .map(|(_, state)| state.upstreams())
+        .unwrap_or_default();
+
+    let assets = match previous {
+        Some((old, state)) if old.assets == config.assets => Arc::clone(state.assets()),
+        _ => Arc::new(Assets::new(config.assets.clone())),
+    };
+    let site = |site: Site| match assets.cache() {
+        Some(cache) => site.with_file_cache(Arc::clone(cache)),
+        None => site,
+    };
+
+    let mut hosts = Hosts::new(config.vhosts.unknown);
+
+    // Without hosts served from it, the default upstream would only hold idle connections
+    if config.vhosts.unknown == Unknown::Default {
+        let default = site(Site::new(
+            config.root_dir.clone(),
+            upstream(
+                &mut upstreams,
+                "default",
+                config.listen,
+                &config.fastcgi,
+                &config.upstream,
+            ),
+        ));
+        hosts = hosts.with_default(Arc::new(default));
+    }
+
+    for host in &config.vhosts.hosts {
+        let upstream = upstream(
+            &mut upstreams,
+            &host.names[0],
+            config.listen,
+            host.fastcgi.as_ref().unwrap_or(&config.fastcgi),
+            host.upstream.as_ref().unwrap_or(&config.upstream),
+        );
+        let site = site(Site::new(host.root_dir.clone(), upstream))
+            .with_routing(host.front_controller.clone(), host.static_paths.clone())
+            .with_headers(host.headers().map_err(|reason| config::Error::Invalid {
+                key: "vhosts.hosts.headers",
+                reason,
+            })?);
+        let site = Arc::new(site);
+
+        for name in &host.names {
+            hosts.add(name, Arc::clone(&site));
+        }
+    }
+
+    // Keep the queue counts and breaker state of requests already in flight
+    let overload = match previous {
+        Some((old, state)) if old.overload == config.overload => Arc::clone(state.overload()),
+        _ => Arc::new(Overload::new(config.overload.clone())),
+    };
+
+    // Read the page again on every reload so it can be changed without a restart
+    let mut maintenance = Maintenance::new(config.maintenance.clone(), &config.root_dir);
+    if let Some(path) = &config.maintenance.page {
+        let page = tokio::fs::read(path)
+            .await
+            .map_err(Error::MaintenancePage)?;
+        maintenance = maintenance.with_page(page.into());
+    }
+    if let Some((_, state)) = previous {
+        maintenance.set_enabled(state.maintenance().is_enabled());
+    }
+
+    // Read the htpasswd files again on every reload to pick up new users
+    let auth = Auth::load(&config.auth).await?;
+
+    let rate_limit = match previous {
+        Some((old, state)) if old.rate_limit == config.rate_limit => state.rate_limit().cloned(),
+        _ => RateLimiter::new(config.rate_limit.clone()).map(Arc::new),
+    };
+
+    let access_log = match previous {
+        Some((old, state)) if old.access_log == config.access_log => state.access_log().cloned(),
+        _ => AccessLog::start(config.access_log.clone())
+            .await?
+            .map(Arc::new),
+    };
+
+    let locations = Locations::new(&config.headers).map_err(|reason| config::Error::Invalid {
+        key: "headers",
+        reason,
+    })?;
+
+    let rewrites = Rewrites::new(&config.rewrites).map_err(|reason| config::Error::Invalid {
+        key: "rewrites",
+        reason,
+    })?;
+
+    let request_ids =
+        RequestIds::new(config.request_id.clone()).map_err(|reason| config::Error::Invalid {
+            key: "request_id.header",
+            reason,
+        })?;
+
+    let mut state = State::new(hosts)
+        .with_assets(assets)
+        .with_locations(locations)
+        .with_rewrites(rewrites)
+        .with_compression(Compressor::new(config.compress.clone()))
+        .with_redirects(Redirects::new(config.internal.clone()))
+        .with_retry(Retry::new(config.retry.clone()))
+        .with_overload(overload)
+        .with_request_ids(request_ids)
+        .with_maintenance(Arc::new(maintenance))
+        .with_path_rules(config.has_path_rules());
+
+    if let Some(auth) = auth {
+        state = state.with_auth(Arc::new(auth));
+    }
+
+    if !config.cors.allowed_origins.is_empty() {
+        let cors = Cors::new(&config.cors).map_err(|reason| config::Error::Invalid {
+            key: "cors",
+            reason,
+        })?;
+        state = state.with_cors(Arc::new(cors));
+    }
+
+    if let Some(rate_limit) = rate_limit {
+        state = state.with_rate_limit(rate_limit);
+    }
+
+    if let Some(access_log) = access_log {
+        state = state.with_access_log(access_log);
+    }
+
+    if config.telemetry.exporter.is_some() {
+        state = state.with_telemetry(config.telemetry.clone());
+    }
+
+    Ok(state)
+}
+
+/// Returns an upstream built from the given settings, reusing a matching one.
+fn upstream(
+    upstreams: &mut Vec<Arc<Upstream>>,
+    name: &str,
+    listen: SocketAddr,
+    fastcgi: &manager::Options,
+    options: &upstream::Options,
+) -> Arc<Upstream> {
+    if let Some(upstream) = upstreams.iter().find(|u| u.matches(fastcgi, options)) {
+        return Arc::clone(upstream);
+    }
+
+    let upstream = Arc::new(Upstream::new(name, listen, fastcgi, options));
+    upstreams.push(Arc::clone(&upstream));
+    upstream
+}
+
+/// Reloads the config on SIGHUP and swaps it into the running service.
+pub struct Reloader {
+    service: PhpService,
+    path: Option<PathBuf>,
+    overrides: Overrides,
+    config: Config,
+}
+
+impl Reloader {
+    pub fn new(
+        service: PhpService,
+        path: Option<PathBuf>,
+        overrides: Overrides,
+        config: Config,
+    ) -> Self {
+        Self {
+            service,
+            path,
+            overrides,
+            config,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to install signal handler");
+
+        while hangup.recv().await.is_some() {
+            tracing::info!({ path = ?self.path }, "reloading config");
+
+            match self.reload().await {
+                Ok(0) => {
+                    tracing::info!("config unchanged, reread maintenance page and htpasswd files")
+                }
+                Ok(changes) => tracing::info!({ changes }, "config reloaded"),
+                Err(e) => {
+                    tracing::error!({ error = %e }, "rejected config, keeping the current one")
+                }
+            }
+        }
+    }
+
+    async fn reload(&mut self) -> Result<usize, Error> {
+        let config = Config::load(self.path.as_deref(), &self.overrides)?;
+        let changes = diff(&self.config, &config);
+        log_changes(&changes);
+
+        // Rebuilt even when the config is unchanged, so files it points to are read again
+        let current = self.service.state();
+        let state = state(&config, Some((&self.config, &current))).await?;
+
+        self.service.swap(state);
+        self.config = config;
+
+        Ok(changes.len())
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.iter().any(|secret| {
+        key.strip_prefix(secret)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+fn log_changes(changes: &[(String, Value, Value)]) {
+    for (key, old, new) in changes {
+        let restart = STATIC_KEYS.iter().any(|k| key.starts_with(k));
+
+        match (restart, is_secret(key)) {
+            (true, true) => tracing::warn!({ key }, "config change requires a restart"),
+            (true, false) => {
+                tracing::warn!({ key, %old, %new }, "config change requires a restart")
+            }
+            (false, true) => tracing::info!({ key }, "config changed"),
+            (false, false) => tracing::info!({ key, %old, %new }, "config changed"),
+        }
+    }
+}
+
+/// Lists the changed leaf values between two configs as `(key, old, new)`.
+fn diff(old: &Config, new: &Config) -> Vec<(String, Value, Value)> {
+    let mut changes = Vec::new();
+    walk(
+        String::new(),
+        &serde_json::to_value(old).expect("config serializes to JSON"),
+        &serde_json::to_value(new).expect("config serializes to JSON"),
+        &mut changes,
+    );
+    changes
+}
+
+/// Compares the keys of both sides, a missing key counts as `null`.
+fn walk(key: String, old: &Value, new: &Value, changes: &mut Vec<(String, Value, Value)>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let names: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+
+            for name in names {
+                let path = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{key}.{name}")
+                };
+
+                walk(
+                    path,
+                    old.get(name).unwrap_or(&Value::Null),
+                    new.get(name).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if old != new => changes.push((key, old.clone(), new.clone())),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn changes(old: Value, new: Value) -> Vec<(String, Value, Value)> {
+        let mut changes = Vec::new();
+        walk(String::new(), &old, &new, &mut changes);
+        changes
+    }
+
+    /// Collects formatted log lines.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_secret_changes_without_values() {
+        let mut old = Config::default();
+        old.admin.token = Some("old-secret".into());
+        let mut new = old.clone();
+        new.admin.token = Some("new-secret".into());
+        new.shutdown_delay += 1;
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || log_changes(&diff(&old, &new)));
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("key=\"admin.token\""), "{logs}");
+        assert!(!logs.contains("secret"), "{logs}");
+        assert!(logs.contains("key=\"shutdown_delay\""), "{logs}");
+        assert!(logs.contains("old=5 new=6"), "{logs}");
+    }
+
+    #[test]
+    fn treats_token_lists_as_secret() {
+        assert!(is_secret("admin.token"));
+        assert!(is_secret("auth.rules"));
+        assert!(is_secret("rewrites"));
+        assert!(!is_secret("admin.listen"));
+        assert!(!is_secret("rewrites_extra"));
+    }
+
+    #[tokio::test]
+    async fn builds_default_upstream_only_for_unknown_hosts() {
+        let mut config = Config::default();
+        config.vhosts.hosts = serde_json::from_value(json!([
+            {
+                "names": ["example.com"],
+                "root_dir": "/srv/example",
+                "upstream": { "backends": ["127.0.0.1:9001"] },
+            }
+        ]))
+        .unwrap();
+
+        let served = state(&config, None).await.unwrap();
+        assert_eq!(served.upstreams().len(), 2);
+
+        config.vhosts.unknown = Unknown::Misdirected;
+        let rejected = state(&config, None).await.unwrap();
+        let upstreams = rejected.upstreams();
+        assert_eq!(upstreams.len(), 1);
+        assert_eq!(upstreams[0].backends()[0].upstream(), "example.com");
+    }
+
+    #[test]
+    fn lists_changed_leaves() {
+        let mut new = Config::default();
+        new.fastcgi.max_conn += 1;
+        new.listen = "0.0.0.0:80".parse().unwrap();
+
+        let keys: Vec<_> = diff(&Config::default(), &new)
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect();
+        assert_eq!(keys, ["fastcgi.max_conn", "listen"]);
+
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn includes_added_and_removed_keys() {
+        assert_eq!(
+            changes(
+                json!({ "a": 1, "b": { "c": 2 } }),
+                json!({ "a": 1, "b": { "d": 3 } }),
+            ),
+            [
+                ("b.c".to_string(), json!(2), Value::Null),
+                ("b.d".to_string(), Value::Null, json!(3)),
+            ]
+        );
+        assert_eq!(
+            changes(json!({}), json!({ "tokens": { "ci": "x" } })),
+            [("tokens".to_string(), Value::Null, json!({ "ci": "x" }))]
+        );
+    }
+
+    #[test]
+    fn compares_lists_whole() {
+        assert_eq!(
+            changes(json!({ "a": [1, 2] }), json!({ "a": [2, 1] })),
+            [("a".to_string(), json!([1, 2]), json!([2, 1]))]
+        );
+    }
+}
@@ -1,13 +1,14 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsStr,
     path::{Path, PathBuf},
 };
 
 use fastcgi_client::Params;
 use futures::TryStreamExt;
-use http::request::Parts;
+use http::{header::COOKIE, request::Parts};
 use http_body_util::BodyExt;
-use hyper::body::Incoming;
+use hyper::body::{Body, Bytes};
 use tokio::io::AsyncRead;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
@@ -31,7 +32,22 @@ impl AsStr for Path {
     }
 }
 
-pub fn find_file(root: &Path, uri_path: &str) -> PathBuf {
+/// FastCGI params added to a request besides the ones derived from it.
+#[derive(Debug, Clone, Default)]
+pub struct ExtraParams(BTreeMap<String, String>);
+
+impl ExtraParams {
+    pub fn insert(&mut self, name: String, value: String) {
+        self.0.insert(name, value);
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+pub fn find_file(root: &Path, front_controller: &Path, uri_path: &str) -> PathBuf {
     let path = root.join(uri_path.trim_start_matches('/'));
 
     if path.is_file() && path.exists() {
@@ -46,15 +62,19 @@ pub fn find_file(root: &Path, uri_path: &str) -> PathBuf {
         }
     }
 
-    root.join("index.php")
+    root.join(front_controller)
 }
 
-pub async fn translate<'a>(
+pub async fn translate<'a, B>(
     root: &'a Path,
     script: &'a Path,
     parts: &'a Parts,
-    body: Incoming,
-) -> fastcgi_client::Request<'a, impl AsyncRead + Unpin> {
+    body: B,
+) -> fastcgi_client::Request<'a, impl AsyncRead + Unpin>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     let mut params = Params::default()
         .document_root(root.as_str())
         .request_method(parts.method.as_str())
@@ -92,13 +112,35 @@ pub async fn translate<'a>(
         }
     }
 
-    for (name, value) in parts.headers.iter() {
+    for name in parts.headers.keys() {
         if matches!(name.as_str(), "host" | "content-type" | "content-length") {
             continue;
         }
 
-        if let Ok(value) = value.to_str() {
-            params = params.custom(format!("HTTP_{}", name.as_str().to_uppercase()), value);
+        // Repeated headers are joined like a single comma separated one, except
+        // cookies, which RFC 6265 separates with `; `
+        let separator = match name == COOKIE {
+            true => "; ",
+            false => ", ",
+        };
+        let values: Vec<_> = parts
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+
+        if !values.is_empty() {
+            params = params.custom(
+                format!("HTTP_{}", name.as_str().to_uppercase()),
+                values.join(separator),
+            );
+        }
+    }
+
+    if let Some(ExtraParams(extra)) = parts.extensions.get::<ExtraParams>() {
+        for (name, value) in extra {
+            params = params.custom(name.as_str(), value.as_str());
         }
     }
 
@@ -107,3 +149,30 @@ pub async fn translate<'a>(
 
     fastcgi_client::Request::new(params, read.compat())
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Empty;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn joins_repeated_headers() {
+        let (parts, _) = http::Request::get("/index.php?a=1")
+            .header("accept", "text/html")
+            .header("accept", "application/json")
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let script = Path::new("/srv/index.php");
+
+        let request = translate(Path::new("/srv"), script, &parts, Empty::<Bytes>::new()).await;
+        let params = request.params();
+
+        assert_eq!(params["HTTP_ACCEPT"], "text/html, application/json");
+        assert_eq!(params["HTTP_COOKIE"], "a=1; b=2");
+        assert_eq!(params["REQUEST_URI"], "/index.php?a=1");
+    }
+}
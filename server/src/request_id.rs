@@ -0,0 +1,101 @@
+use std::fmt;
+
+use http::{HeaderName, HeaderValue, Request};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Longest incoming ID that is kept, longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Header the ID is read from and echoed in
+    pub header: String,
+
+    /// FastCGI param PHP reads the ID from
+    pub param: String,
+
+    /// Keep IDs sent by clients or proxies instead of always generating one
+    pub trust_incoming: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".into(),
+            param: "REQUEST_ID".into(),
+            trust_incoming: true,
+        }
+    }
+}
+
+/// ID correlating the logs of a request in pyper and PHP.
+#[derive(Debug, Clone)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    pub fn value(&self) -> &HeaderValue {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only visible ASCII is accepted or generated
+        f.write_str(self.0.to_str().unwrap_or_default())
+    }
+}
+
+pub struct RequestIds {
+    header: HeaderName,
+    param: String,
+    trust_incoming: bool,
+}
+
+impl RequestIds {
+    pub fn new(options: Options) -> Result<Self, String> {
+        let header = HeaderName::try_from(options.header).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            header,
+            param: options.param,
+            trust_incoming: options.trust_incoming,
+        })
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    pub fn param(&self) -> &str {
+        &self.param
+    }
+
+    /// Takes the ID from the request or generates a UUIDv7, then stores it in the
+    /// request header and extensions.
+    pub fn assign<B>(&self, request: &mut Request<B>) -> RequestId {
+        let incoming = request
+            .headers()
+            .get(&self.header)
+            .filter(|_| self.trust_incoming)
+            .filter(|v| {
+                !v.is_empty()
+                    && v.len() <= MAX_LEN
+                    && v.as_bytes().iter().all(|b| b.is_ascii_graphic())
+            })
+            .cloned();
+
+        let value = incoming.unwrap_or_else(|| {
+            HeaderValue::try_from(Uuid::now_v7().to_string()).expect("UUIDs are valid headers")
+        });
+
+        let id = RequestId(value);
+        request
+            .headers_mut()
+            .insert(self.header.clone(), id.value().clone());
+        request.extensions_mut().insert(id.clone());
+
+        id
+    }
+}
@@ -1,14 +1,26 @@
-use std::str::FromStr;
-
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use httparse::{Header, Status};
 use hyper::{
     body::Bytes,
-    header::{HeaderName, HeaderValue},
-    Response, StatusCode,
+    ext::ReasonPhrase,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, LOCATION},
+    HeaderMap, Response, StatusCode,
 };
 
-use crate::service;
+use crate::{metrics::METRICS, request_id::RequestId, service};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("missing end of headers")]
+    Incomplete,
+    #[error("malformed header line: {0:?}")]
+    Malformed(String),
+    #[error("invalid status: {0:?}")]
+    Status(String),
+    #[error("invalid header name: {0}")]
+    HeaderName(#[from] http::header::InvalidHeaderName),
+    #[error("invalid header value: {0}")]
+    HeaderValue(#[from] http::header::InvalidHeaderValue),
+}
 
 fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, service::Error> {
     Full::new(chunk.into())
@@ -16,42 +28,357 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, service::Error> {
         .boxed()
 }
 
-pub fn parse_status(header: &Header<'_>) -> Option<StatusCode> {
-    if let Some(status) = header.value.iter().position(|c| *c == b' ') {
-        if let Ok(status) = StatusCode::from_bytes(&header.value[..status]) {
-            return Some(status);
+/// Headers that only apply to a single connection and must not be forwarded.
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "proxy-connection"
+            | "te"
+            | "trailer"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
+
+/// Parses the value of a CGI `Status` header, e.g. `404` or `404 Not Found`.
+pub fn parse_status(value: &[u8]) -> Option<(StatusCode, Option<ReasonPhrase>)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+    let status = StatusCode::from_bytes(code.as_bytes()).ok()?;
+    let reason = reason.trim();
+
+    if reason.is_empty() || status.canonical_reason() == Some(reason) {
+        return Some((status, None));
+    }
+
+    Some((status, ReasonPhrase::try_from(reason.as_bytes()).ok()))
+}
+
+/// Splits off the next line, accepting both `\r\n` and bare `\n` line endings.
+fn next_line(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = input.iter().position(|c| *c == b'\n')?;
+    let line = &input[..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    Some((line, &input[end + 1..]))
+}
+
+/// Output of a CGI script split into its status, headers and body.
+pub struct Cgi {
+    pub status: Option<(StatusCode, Option<ReasonPhrase>)>,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Cgi {
+    /// Returns the path of a CGI local redirect (RFC 3875 section 6.2.2), if any.
+    ///
+    /// Only a bare `Location` is a local redirect. With other headers or a body the
+    /// script meant a client redirect, which keeps its cookies and content.
+    pub fn local_redirect(&self) -> Option<&str> {
+        if self.status.is_some() || self.headers.len() != 1 || !self.body.is_empty() {
+            return None;
+        }
+
+        let location = self.headers.get(LOCATION)?.to_str().ok()?;
+
+        if location.starts_with('/') && !location.starts_with("//") {
+            return Some(location);
+        }
+
+        None
+    }
+
+    pub fn into_response(self) -> Response<BoxBody<Bytes, service::Error>> {
+        let mut response = Response::new(full(self.body));
+
+        match self.status {
+            Some((status, reason)) => {
+                *response.status_mut() = status;
+
+                if let Some(reason) = reason {
+                    response.extensions_mut().insert(reason);
+                }
+            }
+            None if self.headers.contains_key(LOCATION) => {
+                *response.status_mut() = StatusCode::FOUND;
+            }
+            None => {}
+        }
+
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+pub fn parse(stdout: Vec<u8>) -> Result<Cgi, Error> {
+    let stdout = Bytes::from(stdout);
+    let mut rest = &stdout[..];
+    let mut status = None;
+    let mut lines: Vec<(HeaderName, Vec<u8>)> = Vec::new();
+
+    loop {
+        let (line, next) = next_line(rest).ok_or(Error::Incomplete)?;
+        rest = next;
+
+        if line.is_empty() {
+            break;
+        }
+
+        // Obsolete line folding, the line continues the previous header value
+        if line[0] == b' ' || line[0] == b'\t' {
+            let (_, value) = lines
+                .last_mut()
+                .ok_or_else(|| Error::Malformed(String::from_utf8_lossy(line).into()))?;
+            value.push(b' ');
+            value.extend_from_slice(line.trim_ascii());
+            continue;
         }
+
+        let colon = line
+            .iter()
+            .position(|c| *c == b':')
+            .ok_or_else(|| Error::Malformed(String::from_utf8_lossy(line).into()))?;
+        let name = HeaderName::from_bytes(&line[..colon])?;
+
+        lines.push((name, line[colon + 1..].trim_ascii().to_vec()));
     }
 
-    None
+    let body = stdout.slice(stdout.len() - rest.len()..);
+    let mut headers = HeaderMap::with_capacity(lines.len());
+
+    for (name, value) in lines {
+        if name.as_str() == "status" {
+            status = Some(
+                parse_status(&value)
+                    .ok_or_else(|| Error::Status(String::from_utf8_lossy(&value).into()))?,
+            );
+            continue;
+        }
+
+        if is_hop_by_hop(&name) {
+            tracing::debug!(
+                { header = name.as_str() },
+                "dropping hop-by-hop header from script"
+            );
+            continue;
+        }
+
+        if name == CONTENT_LENGTH && value != body.len().to_string().as_bytes() {
+            tracing::debug!("dropping content-length header not matching script output");
+            continue;
+        }
+
+        headers.append(name, HeaderValue::from_bytes(&value)?);
+    }
+
+    Ok(Cgi {
+        status,
+        headers,
+        body,
+    })
 }
 
 pub fn translate(
     input: fastcgi_client::Response,
-) -> Result<Response<BoxBody<Bytes, service::Error>>, crate::service::Error> {
-    let mut response = Response::new(BoxBody::default());
-    let mut stdout = input.stdout.unwrap_or_default();
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-
-    if let Status::Complete((offset, headers)) = httparse::parse_headers(&stdout, &mut headers)? {
-        for header in headers {
-            match header.name {
-                "Status" => {
-                    if let Some(status) = parse_status(&header) {
-                        *response.status_mut() = status;
-                    }
-                }
-                _ => {
-                    response.headers_mut().append(
-                        HeaderName::from_str(header.name)?,
-                        HeaderValue::from_bytes(header.value)?,
-                    );
-                }
+    request_id: Option<&RequestId>,
+) -> Result<Cgi, Error> {
+    if let Some(stderr) = &input.stderr {
+        for line in String::from_utf8_lossy(stderr).lines() {
+            if !line.is_empty() {
+                METRICS.stderr_lines.inc();
+                tracing::warn!({ line, request_id = request_id.map(tracing::field::display) }, "script stderr");
             }
         }
+    }
 
-        *response.body_mut() = full(stdout.drain(offset..).collect::<Bytes>());
+    parse(input.stdout.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgi(output: &str) -> Cgi {
+        parse(output.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn parses_status() {
+        let status = |value: &str| parse_status(value.as_bytes());
+
+        assert_eq!(status("404"), Some((StatusCode::NOT_FOUND, None)));
+        assert_eq!(
+            status(" 404 Not Found "),
+            Some((StatusCode::NOT_FOUND, None))
+        );
+        assert_eq!(status("abc"), None);
+        assert_eq!(status("1000 Too Big"), None);
+
+        let (code, reason) = status("299 Custom Thing").unwrap();
+        assert_eq!(code.as_u16(), 299);
+        assert_eq!(reason.unwrap().as_bytes(), b"Custom Thing");
+    }
+
+    #[test]
+    fn status_header_sets_the_response_status() {
+        let response =
+            cgi("Status: 404\r\nContent-Type: text/plain\r\n\r\nmissing").into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!response.headers().contains_key("status"));
+
+        let response = cgi("Status: 418 Short And Stout\r\n\r\n").into_response();
+        let reason = response.extensions().get::<ReasonPhrase>().unwrap();
+        assert_eq!(reason.as_bytes(), b"Short And Stout");
+    }
+
+    #[test]
+    fn rejects_invalid_status() {
+        let result = parse(b"Status: nope\r\n\r\n".to_vec());
+        assert!(matches!(result, Err(Error::Status(_))));
+    }
+
+    #[test]
+    fn defaults_to_ok() {
+        let cgi = cgi("Content-Type: text/html\n\nhello");
+
+        assert_eq!(cgi.body, "hello");
+        assert_eq!(cgi.into_response().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        let cgi = cgi("X-Fold: a\r\n  b\r\n\tc\r\nX-Other: d\r\n\r\n");
+
+        assert_eq!(cgi.headers["x-fold"], "a b c");
+        assert_eq!(cgi.headers["x-other"], "d");
+    }
+
+    #[test]
+    fn rejects_continuation_without_header() {
+        let result = parse(b" folded\r\n\r\n".to_vec());
+        assert!(matches!(result, Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn accepts_many_headers() {
+        let headers: String = (0..200).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+        let cgi = cgi(&format!("{headers}\r\nbody"));
+
+        assert_eq!(cgi.headers.len(), 200);
+        assert_eq!(cgi.headers["x-header-199"], "199");
+        assert_eq!(cgi.body, "body");
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let cgi = cgi("Set-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\n");
+        let cookies: Vec<_> = cgi.headers.get_all("set-cookie").iter().collect();
+
+        assert_eq!(cookies, ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn rejects_missing_terminator() {
+        assert!(matches!(
+            parse(b"Content-Type: text/html\r\n".to_vec()),
+            Err(Error::Incomplete)
+        ));
+        assert!(matches!(parse(b"hello".to_vec()), Err(Error::Incomplete)));
+        assert!(matches!(parse(Vec::new()), Err(Error::Incomplete)));
     }
 
-    Ok(response)
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(matches!(
+            parse(b"no colon\r\n\r\n".to_vec()),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            parse(b"Bad Name: x\r\n\r\n".to_vec()),
+            Err(Error::HeaderName(_))
+        ));
+        assert!(matches!(
+            parse(b"X-Bad: a\x01b\r\n\r\n".to_vec()),
+            Err(Error::HeaderValue(_))
+        ));
+    }
+
+    #[test]
+    fn drops_hop_by_hop_headers() {
+        let cgi = cgi("Connection: close\r\nTransfer-Encoding: chunked\r\nX-Kept: 1\r\n\r\n");
+
+        assert!(!cgi.headers.contains_key("connection"));
+        assert!(!cgi.headers.contains_key("transfer-encoding"));
+        assert_eq!(cgi.headers["x-kept"], "1");
+    }
+
+    #[test]
+    fn drops_wrong_content_length() {
+        assert!(!cgi("Content-Length: 10\r\n\r\nabc")
+            .headers
+            .contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            cgi("Content-Length: 3\r\n\r\nabc").headers[CONTENT_LENGTH],
+            "3"
+        );
+    }
+
+    #[test]
+    fn location_without_status_is_found() {
+        let response = cgi("Location: https://example.com/\r\n\r\n").into_response();
+
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[LOCATION], "https://example.com/");
+    }
+
+    #[test]
+    fn location_with_status_keeps_it() {
+        let response = cgi("Status: 301\r\nLocation: /moved\r\n\r\n").into_response();
+
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[test]
+    fn bare_location_is_a_local_redirect() {
+        assert_eq!(
+            cgi("Location: /next?a=1\r\n\r\n").local_redirect(),
+            Some("/next?a=1")
+        );
+    }
+
+    #[test]
+    fn location_with_other_headers_is_a_client_redirect() {
+        let cgi = cgi("Location: /login\r\nSet-Cookie: a=1\r\n\r\n");
+        assert_eq!(cgi.local_redirect(), None);
+
+        let response = cgi.into_response();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()["set-cookie"], "a=1");
+    }
+
+    #[test]
+    fn location_with_body_is_a_client_redirect() {
+        let cgi = cgi("Location: /login\r\n\r\nmoved");
+        assert_eq!(cgi.local_redirect(), None);
+        assert_eq!(cgi.into_response().status(), StatusCode::FOUND);
+    }
+
+    #[test]
+    fn location_with_status_or_host_is_not_local() {
+        assert_eq!(
+            cgi("Status: 301\r\nLocation: /a\r\n\r\n").local_redirect(),
+            None
+        );
+        assert_eq!(cgi("Location: //evil.com/a\r\n\r\n").local_redirect(), None);
+        assert_eq!(
+            cgi("Location: https://example.com/\r\n\r\n").local_redirect(),
+            None
+        );
+    }
 }
@@ -0,0 +1,215 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use fastcgi_client::{ClientError, ClientResult, Response};
+use http::{header::CONTENT_LENGTH, request::Parts, Method};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Bytes};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    manager::{self, Conn},
+    metrics::METRICS,
+    request, service,
+    upstream::Backend,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Retry requests that failed because FPM had closed the connection
+    pub enabled: bool,
+
+    /// Attempts per request, including the first one
+    pub max_attempts: u32,
+
+    /// Request bodies up to this many bytes are buffered so they can be replayed
+    pub max_body_size: u64,
+
+    /// Retries earned by every request, limits retries to a fraction of the traffic
+    pub budget_ratio: f64,
+
+    /// Retries that can be spent in a burst
+    pub budget_burst: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 2,
+            max_body_size: 64 * 1024,
+            budget_ratio: 0.2,
+            budget_burst: 10.0,
+        }
+    }
+}
+
+/// Methods that can be repeated without side effects.
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn is_connection_error(error: &ClientError) -> bool {
+    matches!(error, ClientError::Io(e) if manager::is_closed(e.kind()))
+}
+
+async fn send<B>(
+    conn: &mut Conn,
+    root: &Path,
+    file: &Path,
+    parts: &Parts,
+    body: B,
+) -> ClientResult<Response>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let request = request::translate(root, file, parts, body).await;
+    conn.send(request).await
+}
+
+/// Retries requests failing on a connection FPM closed before answering, within a budget.
+pub struct Retry {
+    options: Options,
+    tokens: Mutex<f64>,
+}
+
+impl Retry {
+    pub fn new(options: Options) -> Self {
+        Self {
+            tokens: Mutex::new(options.budget_burst),
+            options,
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.options.budget_ratio).min(self.options.budget_burst);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        if *tokens < 1.0 {
+            return false;
+        }
+
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Buffers small request bodies so they can be sent again.
+    async fn replayable(
+        &self,
+        parts: &Parts,
+        body: BoxBody<Bytes, hyper::Error>,
+    ) -> Result<Result<Bytes, BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if body.is_end_stream() {
+            return Ok(Ok(Bytes::new()));
+        }
+
+        let length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+
+        match length {
+            Some(length) if length <= self.options.max_body_size => {
+                Ok(Ok(body.collect().await?.to_bytes()))
+            }
+            _ => Ok(Err(body)),
+        }
+    }
+
+    /// Sends the request over `conn`, retrying on fresh connections to `backend`.
+    pub async fn execute(
+        &self,
+        backend: &Backend,
+        conn: &mut Conn,
+        root: &Path,
+        file: &Path,
+        parts: &Parts,
+        body: BoxBody<Bytes, hyper::Error>,
+    ) -> Result<Response, service::Error> {
+        if !self.options.enabled || self.options.max_attempts <= 1 {
+            return send(conn, root, file, parts, body)
+                .await
+                .map_err(Into::into);
+        }
+
+        self.deposit();
+
+        let bytes = match self.replayable(parts, body).await? {
+            Ok(bytes) => bytes,
+            Err(body) => {
+                return send(conn, root, file, parts, body)
+                    .await
+                    .map_err(Into::into)
+            }
+        };
+
+        let mut fresh: Option<Conn> = None;
+        let mut attempt = 1;
+
+        loop {
+            let conn = match fresh.as_mut() {
+                Some(fresh) => fresh,
+                None => &mut *conn,
+            };
+            let consumed = Arc::new(AtomicBool::new(false));
+            let body = {
+                let consumed = Arc::clone(&consumed);
+
+                Full::new(bytes.clone()).map_frame(move |frame| {
+                    consumed.store(true, Ordering::Relaxed);
+                    frame
+                })
+            };
+
+            let result = send(conn, root, file, parts, body).await;
+
+            let Err(error) = &result else {
+                return result.map_err(Into::into);
+            };
+
+            let retryable = attempt < self.options.max_attempts
+                && is_connection_error(error)
+                && !conn.received()
+                && (is_safe(&parts.method) || !consumed.load(Ordering::Relaxed));
+
+            if !retryable {
+                return result.map_err(Into::into);
+            }
+
+            if !self.withdraw() {
+                METRICS
+                    .retries
+                    .with_label_values(&["budget_exhausted"])
+                    .inc();
+                return result.map_err(Into::into);
+            }
+
+            tracing::warn!({ %error, attempt }, "retrying request on a fresh connection");
+            METRICS.retries.with_label_values(&["retried"]).inc();
+
+            fresh = match backend.pool().dedicated_connection().await {
+                Ok(conn) => Some(conn),
+                Err(e) => {
+                    tracing::warn!({ error = %e }, "failed to open a fresh connection");
+                    return result.map_err(Into::into);
+                }
+            };
+            attempt += 1;
+        }
+    }
+}
@@ -1,18 +1,49 @@
-use std::{convert::Infallible, future::Future, path::PathBuf, pin::Pin};
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Instant,
+};
 
-use bb8::Pool;
-use http::StatusCode;
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use arc_swap::ArcSwap;
+use fastcgi_client::ClientError;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HOST, ORIGIN, RETRY_AFTER},
+    StatusCode,
+};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{
     body::{Bytes, Incoming},
     service::Service,
     Request, Response,
 };
-use hyper_staticfile::Static;
+use hyper_staticfile::AcceptEncoding;
+use tracing::Instrument;
 
 use crate::{
-    manager::{self, Manager},
-    request, service,
+    access_log::{AccessLog, Record, UpstreamTime},
+    assets::{self, Assets},
+    auth::{Auth, User},
+    compress::{Accept, Compressor},
+    cors::Cors,
+    headers::{Locations, Rewrites},
+    internal::Redirects,
+    maintenance::Maintenance,
+    manager,
+    metrics::{self, METRICS},
+    overload::{Overload, Shed},
+    paths,
+    rate_limit::{Limited, RateLimiter},
+    request::{self, ExtraParams},
+    request_id::{RequestId, RequestIds},
+    response,
+    retry::Retry,
+    telemetry,
+    upstream::Upstream,
+    vhost::{Hosts, Site},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -23,14 +54,30 @@ pub enum Error {
     Pool(#[from] bb8::RunError<manager::Error>),
     #[error("fastcgi error: {0}")]
     FastCgi(#[from] fastcgi_client::ClientError),
+    #[error("failed to read request body: {0}")]
+    Body(#[from] hyper::Error),
     #[error("failed to join task: {0}")]
     Join(#[from] tokio::task::JoinError),
-    #[error("failed to parse headers: {0}")]
-    Headers(#[from] httparse::Error),
-    #[error("failed to parse header name: {0}")]
-    HeaderName(#[from] http::header::InvalidHeaderName),
-    #[error("failed to parse header value: {0}")]
-    HeaderValue(#[from] http::header::InvalidHeaderValue),
+    #[error("invalid script response: {0}")]
+    Response(#[from] response::Error),
+    #[error("invalid local redirect: {0}")]
+    Location(#[from] http::uri::InvalidUri),
+    #[error("too many local redirects, last to {0}")]
+    Redirects(String),
+    #[error("{0}")]
+    Shed(#[from] Shed),
+    #[error("{0}")]
+    RateLimited(#[from] Limited),
+}
+
+fn plain(status: StatusCode, body: &'static str) -> Response<BoxBody<Bytes, Error>> {
+    let mut response = Response::new(
+        Full::new(Bytes::from(body))
+            .map_err(|_| unreachable!())
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response
 }
 
 fn handle_result(
@@ -38,36 +85,509 @@ fn handle_result(
 ) -> Result<Response<BoxBody<Bytes, Error>>, Infallible> {
     match result {
         Ok(response) => Ok(response),
+        Err(Error::Response(e)) => {
+            tracing::error!({ reason = %e }, "malformed response from script");
+
+            Ok(plain(StatusCode::BAD_GATEWAY, "bad gateway"))
+        }
+        Err(Error::Shed(shed)) => {
+            tracing::debug!({ reason = shed.reason }, "shedding request");
+
+            let mut response = plain(StatusCode::SERVICE_UNAVAILABLE, "service unavailable");
+            let retry_after = (shed.retry_after.as_secs_f64().ceil() as u64).max(1);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.into());
+
+            Ok(response)
+        }
+        Err(Error::RateLimited(limited)) => {
+            tracing::debug!("rate limiting request");
+
+            let mut response = plain(StatusCode::TOO_MANY_REQUESTS, "too many requests");
+            limited.quota.apply(response.headers_mut());
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, limited.retry_after.into());
+
+            Ok(response)
+        }
         Err(e) => {
             tracing::error!({ error = ?e }, "failed to handle request");
 
-            let mut response = Response::new(
-                Full::new(Bytes::from("internal server error"))
-                    .map_err(|_| unreachable!())
-                    .boxed(),
-            );
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(plain(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal server error",
+            ))
+        }
+    }
+}
 
-            Ok(response)
+/// Whether a response was produced by the static file handler or by PHP.
+#[derive(Debug, Clone, Copy)]
+pub enum Route {
+    Static,
+    Php,
+}
+
+impl Route {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Route::Static => "static",
+            Route::Php => "php",
+        }
+    }
+}
+
+fn php(
+    mut response: Response<BoxBody<Bytes, Error>>,
+    upstream: UpstreamTime,
+) -> Response<BoxBody<Bytes, Error>> {
+    response.extensions_mut().insert(Route::Php);
+    response.extensions_mut().insert(upstream);
+    response
+}
+
+/// Everything a config reload may replace, requests keep the state they started with.
+pub struct State {
+    hosts: Hosts,
+    assets: Arc<Assets>,
+    locations: Locations,
+    rewrites: Rewrites,
+    retry: Arc<Retry>,
+    overload: Arc<Overload>,
+    request_ids: Arc<RequestIds>,
+    maintenance: Arc<Maintenance>,
+    compressor: Option<Arc<Compressor>>,
+    redirects: Option<Arc<Redirects>>,
+    auth: Option<Arc<Auth>>,
+    cors: Option<Arc<Cors>>,
+    rate_limit: Option<Arc<RateLimiter>>,
+    telemetry: Option<Arc<telemetry::Options>>,
+    access_log: Option<Arc<AccessLog>>,
+    /// Whether paths that can't be normalized are rejected
+    path_rules: bool,
+}
+
+impl State {
+    pub fn new(hosts: Hosts) -> Self {
+        Self {
+            hosts,
+            assets: Arc::new(Assets::new(Default::default())),
+            locations: Locations::default(),
+            rewrites: Rewrites::default(),
+            retry: Arc::new(Retry::new(Default::default())),
+            overload: Arc::new(Overload::new(Default::default())),
+            request_ids: Arc::new(
+                RequestIds::new(Default::default()).expect("default header is valid"),
+            ),
+            maintenance: Arc::new(Maintenance::new(Default::default(), Path::new("."))),
+            compressor: None,
+            redirects: None,
+            auth: None,
+            cors: None,
+            rate_limit: None,
+            telemetry: None,
+            access_log: None,
+            path_rules: false,
+        }
+    }
+
+    pub fn with_compression(mut self, compressor: Compressor) -> Self {
+        self.compressor = Some(Arc::new(compressor));
+        self
+    }
+
+    pub fn with_redirects(mut self, redirects: Redirects) -> Self {
+        self.redirects = Some(Arc::new(redirects));
+        self
+    }
+
+    pub fn with_assets(mut self, assets: Arc<Assets>) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    pub fn with_locations(mut self, locations: Locations) -> Self {
+        self.locations = locations;
+        self
+    }
+
+    pub fn with_rewrites(mut self, rewrites: Rewrites) -> Self {
+        self.rewrites = rewrites;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = Arc::new(retry);
+        self
+    }
+
+    pub fn with_overload(mut self, overload: Arc<Overload>) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    pub fn with_maintenance(mut self, maintenance: Arc<Maintenance>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    pub fn with_request_ids(mut self, request_ids: RequestIds) -> Self {
+        self.request_ids = Arc::new(request_ids);
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Arc<Auth>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn with_cors(mut self, cors: Arc<Cors>) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Arc<RateLimiter>) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Passes the trace context to PHP, for when spans are exported.
+    pub fn with_telemetry(mut self, telemetry: telemetry::Options) -> Self {
+        self.telemetry = Some(Arc::new(telemetry));
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Rejects paths that path rules can't match with `400`, as they would slip
+    /// past them.
+    pub fn with_path_rules(mut self, path_rules: bool) -> Self {
+        self.path_rules = path_rules;
+        self
+    }
+
+    /// Upstreams of all hosts, each listed once.
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
+
+        for site in self.hosts.sites() {
+            if !upstreams.iter().any(|u| Arc::ptr_eq(u, &site.upstream)) {
+                upstreams.push(Arc::clone(&site.upstream));
+            }
+        }
+
+        upstreams
+    }
+
+    pub fn assets(&self) -> &Arc<Assets> {
+        &self.assets
+    }
+
+    pub fn overload(&self) -> &Arc<Overload> {
+        &self.overload
+    }
+
+    pub fn rate_limit(&self) -> Option<&Arc<RateLimiter>> {
+        self.rate_limit.as_ref()
+    }
+
+    pub fn maintenance(&self) -> &Arc<Maintenance> {
+        &self.maintenance
+    }
+
+    pub fn access_log(&self) -> Option<&Arc<AccessLog>> {
+        self.access_log.as_ref()
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        mut request: Request<BoxBody<Bytes, hyper::Error>>,
+        redirects: usize,
+    ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+        // Path rules match the normalized path, which unservable paths don't have
+        if self.path_rules && paths::normalize(request.uri().path()).is_none() {
+            tracing::debug!({ path = request.uri().path() }, "rejecting malformed path");
+            return Ok(plain(StatusCode::BAD_REQUEST, "bad request"));
+        }
+
+        // Local redirects carry the user and rewritten headers of the original request
+        if redirects == 0 {
+            if let Some(response) = self.maintenance.check(&request) {
+                return Ok(response.map(|body| body.map_err(|never| match never {}).boxed()));
+            }
+
+            // Preflights carry no credentials, answer them before authentication
+            if let Some(response) = self.cors.as_ref().and_then(|c| c.preflight(&request)) {
+                return Ok(response.map(|body| body.map_err(|never| match never {}).boxed()));
+            }
+
+            if let Some(auth) = &self.auth {
+                if let Err(response) = auth.check(&mut request).await {
+                    return Ok(response.map(|body| body.map_err(|never| match never {}).boxed()));
+                }
+            }
+
+            let path = request.uri().path().to_string();
+            self.rewrites.request(&path, request.headers_mut());
+        }
+
+        let user = request.extensions().get::<User>().cloned();
+
+        let site = match self.hosts.find(&request) {
+            Ok(site) => Arc::clone(site),
+            Err(status) => {
+                tracing::debug!({ host = ?request.headers().get(HOST) }, "unknown host");
+                return Ok(plain(status, status.canonical_reason().unwrap_or_default()));
+            }
+        };
+
+        let path = request.uri().path().to_string();
+        let headers = site.headers.clone();
+        let mut response = Arc::clone(&self)
+            .serve_site(site, request, redirects)
+            .await?;
+
+        // The response of a local redirect is finished once, for the original request
+        if redirects > 0 {
+            return Ok(response);
+        }
+
+        self.locations.apply(&path, response.headers_mut());
+        response.headers_mut().extend(headers);
+        self.rewrites.response(&path, response.headers_mut());
+
+        // Tells the access log who made the request
+        if let Some(user) = user {
+            response.extensions_mut().insert(user);
+        }
+
+        Ok(response)
+    }
+
+    async fn serve_site(
+        self: Arc<Self>,
+        site: Arc<Site>,
+        request: Request<BoxBody<Bytes, hyper::Error>>,
+        redirects: usize,
+    ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+        let path = request.uri().path();
+        let file = request::find_file(&site.root, &site.front_controller, path);
+
+        if site.static_paths.is_match(path) || file.extension() != Some("php".as_ref()) {
+            let mut files = site.files.clone();
+
+            if self.compressor.as_ref().is_some_and(|c| c.precompressed()) {
+                files.allowed_encodings(AcceptEncoding::all());
+            }
+
+            let method = request.method().clone();
+            let path = request.uri().path().to_string();
+            let headers = request.headers().clone();
+
+            let mut response = assets::serve(&files, &request)
+                .await?
+                .map(|body| body.map_err(Into::into).boxed());
+            response.extensions_mut().insert(Route::Static);
+
+            return Ok(self.assets.respond(&method, &path, &headers, response));
+        }
+
+        // Local redirects were already counted with the original request
+        let quota = match &self.rate_limit {
+            Some(limiter) if redirects == 0 => limiter.check(&request)?,
+            _ => None,
+        };
+
+        let mut response = self.fastcgi(site, file, request, redirects).await?;
+
+        if let Some(quota) = quota {
+            quota.apply(response.headers_mut());
+        }
+
+        Ok(response)
+    }
+
+    async fn fastcgi(
+        self: Arc<Self>,
+        site: Arc<Site>,
+        file: PathBuf,
+        request: Request<BoxBody<Bytes, hyper::Error>>,
+        redirects: usize,
+    ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+        let (mut parts, body) = request.into_parts();
+        let mut original = Request::new(());
+        *original.method_mut() = parts.method.clone();
+        *original.uri_mut() = parts.uri.clone();
+        *original.headers_mut() = parts.headers.clone();
+
+        let backend = site.upstream.pick(&parts);
+        let listen_queue = backend.fpm_status().map(|status| status.listen_queue);
+        let ticket = self.overload.admit(
+            self.overload.lane(parts.uri.path()),
+            backend.upstream(),
+            listen_queue,
+        )?;
+        let overload = Arc::clone(&self.overload);
+        let root = site.root.clone();
+        let retry = Arc::clone(&self.retry);
+        let telemetry = self.telemetry.clone();
+        let request_id = parts.extensions.get::<RequestId>().cloned();
+        let user = parts.extensions.get::<User>().cloned();
+
+        let mut params = ExtraParams::default();
+        self.rewrites.params(parts.uri.path(), &mut params);
+        if let Some(id) = &request_id {
+            params.insert(self.request_ids.param().to_string(), id.to_string());
+        }
+        if let Some(user) = &user {
+            params.insert("REMOTE_USER".into(), user.name.clone());
+            params.insert("AUTH_TYPE".into(), user.scheme.as_str().into());
+        }
+        let start = Instant::now();
+
+        // Make sure the connection is not dropped when the future is dropped
+        let handle = tokio::spawn(
+            async move {
+                let _outstanding = backend.start();
+                let timer = METRICS.checkout.start_timer();
+                let checkout = tokio::time::timeout(overload.max_wait(), async {
+                    let permit = overload.reserve(ticket.lane(), &backend).await;
+                    backend.get().await.map(|conn| (permit, conn))
+                })
+                .instrument(tracing::info_span!("pool checkout"));
+                // The permit outlives the connection, which goes back to the pool first
+                let (_permit, mut conn) = match checkout.await {
+                    Ok(Ok(checked_out)) => checked_out,
+                    // Failed connects were already counted against the backend by the pool
+                    Ok(Err(e)) => {
+                        overload.failure(backend.upstream());
+                        return Err(e.into());
+                    }
+                    Err(_) => return Err(overload.timed_out().into()),
+                };
+                timer.observe_duration();
+                drop(ticket);
+
+                tracing::debug!({ ?file, path = parts.uri.path() }, "calling script for request");
+
+                let span = tracing::info_span!("fastcgi send", backend = %backend.addr());
+
+                if let Some(telemetry) = telemetry {
+                    telemetry::inject(&telemetry, &span, &mut params);
+                }
+                parts.extensions.insert(params);
+
+                let body = metrics::count(body, METRICS.bytes_in.with_label_values(&["php"]));
+                let result = retry
+                    .execute(&backend, &mut conn, &root, &file, &parts, body)
+                    .instrument(span)
+                    .await;
+
+                match &result {
+                    Ok(_) => {
+                        backend.success();
+                        overload.success(backend.upstream());
+                    }
+                    Err(Error::FastCgi(e)) => {
+                        METRICS.fastcgi_error(e);
+                        backend.failure();
+
+                        if matches!(
+                            e,
+                            ClientError::Io(_) | ClientError::EndRequestOverloaded { .. }
+                        ) {
+                            overload.failure(backend.upstream());
+                        }
+                    }
+                    Err(_) => {}
+                }
+
+                result
+            }
+            .instrument(tracing::Span::current()),
+        );
+
+        let output = handle.await??;
+        let upstream = UpstreamTime(start.elapsed());
+        let cgi = response::translate(output, request_id.as_ref())?;
+
+        let Some(internal) = self.redirects.clone() else {
+            return Ok(php(cgi.into_response(), upstream));
+        };
+
+        let local = cgi
+            .local_redirect()
+            .filter(|_| internal.max_redirects() > 0);
+
+        if let Some(location) = local {
+            if redirects >= internal.max_redirects() {
+                return Err(Error::Redirects(location.to_string()));
+            }
+
+            tracing::debug!({ location }, "following local redirect");
+
+            let mut request = Request::new(Empty::new().map_err(|never| match never {}).boxed());
+            *request.uri_mut() = location.parse()?;
+            *request.headers_mut() = original.headers().clone();
+            request.headers_mut().remove(CONTENT_TYPE);
+            request.headers_mut().remove(CONTENT_LENGTH);
+
+            if let Some(id) = request_id {
+                request.extensions_mut().insert(id);
+            }
+            if let Some(user) = user {
+                request.extensions_mut().insert(user);
+            }
+
+            return Box::pin(self.serve(request, redirects + 1)).await;
         }
+
+        internal
+            .sendfile(&original, cgi.into_response())
+            .await
+            .map(|response| php(response, upstream))
     }
 }
 
 #[derive(Clone)]
 pub struct PhpService {
-    root: PathBuf,
-    files: Static,
-    pool: Pool<Manager>,
+    state: Arc<ArcSwap<State>>,
+    remote: Option<SocketAddr>,
 }
 
 impl PhpService {
-    pub fn new(pool: Pool<Manager>, root: PathBuf) -> Self {
+    pub fn new(state: State) -> Self {
+        Self {
+            state: Arc::new(ArcSwap::from_pointee(state)),
+            remote: None,
+        }
+    }
+
+    /// Returns a clone of the service for a connection from `remote`.
+    pub fn for_connection(&self, remote: SocketAddr) -> Self {
         Self {
-            pool,
-            files: Static::new(&root),
-            root,
+            remote: Some(remote),
+            ..self.clone()
         }
     }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.load_full()
+    }
+
+    /// Swaps the state for all clones of the service, including running connections.
+    pub fn swap(&self, state: State) {
+        self.state.store(Arc::new(state));
+    }
+
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        self.state.load().upstreams()
+    }
 }
 
 impl Service<Request<Incoming>> for PhpService {
@@ -75,33 +595,182 @@ impl Service<Request<Incoming>> for PhpService {
     type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, request: Request<Incoming>) -> Self::Future {
-        let root = self.root.clone();
-        let pool = self.pool.clone();
-        let files = self.files.clone();
-        let future = async move {
-            let file = request::find_file(&root, request.uri().path());
+    fn call(&self, mut request: Request<Incoming>) -> Self::Future {
+        let start = Instant::now();
+        let state = self.state.load_full();
+        let compressor = state.compressor.clone();
+        let access_log = state.access_log.clone();
+        let request_ids = Arc::clone(&state.request_ids);
+        let request_id = request_ids.assign(&mut request);
+        let record = access_log
+            .as_ref()
+            .map(|_| Record::new(&request, self.remote));
+        let accept = Accept::new(&mut request);
+        let cors = state.cors.clone().map(|cors| {
+            let origin = request.headers().get(ORIGIN).cloned();
+            (cors, origin, request.uri().path().to_string())
+        });
+
+        if let Some(remote) = self.remote {
+            request.extensions_mut().insert(remote);
+        }
+
+        let span = tracing::info_span!(
+            "request",
+            otel.name = %format_args!("{} {}", request.method(), request.uri().path()),
+            otel.kind = "server",
+            http.request.method = %request.method(),
+            url.path = request.uri().path(),
+            http.response.status_code = tracing::field::Empty,
+            request_id = %request_id,
+        );
+        telemetry::continue_trace(&span, request.headers());
+
+        let future = state
+            .serve(request.map(BodyExt::boxed), 0)
+            .instrument(tracing::info_span!(parent: &span, "time to first byte"));
 
-            if file.extension() != Some("php".as_ref()) {
-                let response = files.serve(request).await?;
-                return Ok(response.map(|body| body.map_err(Into::into).boxed()));
+        Box::pin(async move {
+            let mut response = handle_result(future.await)?;
+            response
+                .headers_mut()
+                .insert(request_ids.header().clone(), request_id.value().clone());
+
+            if let Some((cors, origin, path)) = cors {
+                cors.apply(origin.as_ref(), &path, &mut response);
             }
 
-            // Make sure the connection is not dropped when the future is dropped
-            let handle = tokio::spawn(async move {
-                let (parts, body) = request.into_parts();
-                let mut conn = pool.get().await?;
+            let response = match compressor {
+                Some(compressor) => compressor.encode(&accept, response),
+                None => response,
+            };
 
-                tracing::debug!({ ?file, path = parts.uri.path() }, "calling script for request");
+            let route = response
+                .extensions()
+                .get::<Route>()
+                .copied()
+                .unwrap_or(Route::Php);
+            let status = response.status();
+            let labels = [route.as_str(), status.as_str()];
 
-                let request = request::translate(&root, &file, &parts, body).await;
-                Ok::<_, service::Error>(conn.send(request).await?)
-            });
+            METRICS.requests.with_label_values(&labels).inc();
+            METRICS
+                .duration
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
 
-            let response = handle.await??;
-            crate::response::translate(response)
-        };
+            let bytes_out = METRICS.bytes_out.with_label_values(&[route.as_str()]);
+            let response = response.map(|body| metrics::count(body, bytes_out));
+
+            span.record("http.response.status_code", status.as_u16());
+            let response = match span.is_disabled() {
+                true => response,
+                false => {
+                    let body = tracing::info_span!(parent: &span, "body");
+                    response.map(|b| telemetry::hold(b, vec![span, body]))
+                }
+            };
+
+            Ok(match (access_log, record) {
+                (Some(access_log), Some(record)) => access_log.wrap(record, response),
+                _ => response,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{headers, manager::tests::respond, upstream, vhost::Unknown};
+
+    const OK: &[u8] = b"Content-Type: text/plain\r\n\r\nok";
+
+    /// Answers script requests with `replies` in turn, repeating the last one.
+    pub(crate) async fn backend(replies: &'static [&'static [u8]]) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let requests = Arc::clone(&requests);
+                tokio::spawn(async move {
+                    loop {
+                        let n = requests.fetch_add(1, Ordering::Relaxed);
+                        let stdout = replies[n.min(replies.len() - 1)];
+                        if respond(&mut stream, stdout).await.is_none() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    async fn state(replies: &'static [&'static [u8]]) -> State {
+        let upstream = Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &manager::Options::default(),
+            &upstream::Options {
+                backends: vec![backend(replies).await],
+                ..upstream::Options::default()
+            },
+        );
+        let site = Site::new("/nonexistent".into(), Arc::new(upstream));
+        State::new(Hosts::new(Unknown::Default).with_default(Arc::new(site)))
+            .with_redirects(Redirects::new(Default::default()))
+    }
 
-        Box::pin(async move { handle_result(future.await) })
+    async fn get(state: &Arc<State>, path: &str) -> Response<BoxBody<Bytes, Error>> {
+        let request = Request::builder()
+            .uri(path)
+            .body(Empty::new().map_err(|never| match never {}).boxed())
+            .unwrap();
+        Arc::clone(state).serve(request, 0).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn finishes_local_redirects_once() {
+        let rewrites = Rewrites::new(&[headers::Rule {
+            paths: Default::default(),
+            request: Default::default(),
+            params: Default::default(),
+            response: headers::Edit {
+                append: [("x-rewritten".to_string(), "1".to_string())].into(),
+                ..Default::default()
+            },
+        }])
+        .unwrap();
+        let state = state(&[b"Location: /next\r\n\r\n", OK])
+            .await
+            .with_rewrites(rewrites);
+
+        let response = get(&Arc::new(state), "/start").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get_all("x-rewritten").iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn passes_unnormalized_paths_without_path_rules() {
+        let response = get(&Arc::new(state(&[OK]).await), "/caf%E9").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_unnormalized_paths_with_path_rules() {
+        let state = Arc::new(state(&[OK]).await.with_path_rules(true));
+
+        for path in ["/caf%E9", "/%zz", "/a/../.."] {
+            let response = get(&state, path).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+        }
     }
 }
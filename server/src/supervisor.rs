@@ -0,0 +1,393 @@
+use std::{
+    fs::{DirBuilder, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use nix::{sys::signal::Signal, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpStream,
+    process::{Child, Command},
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{manager, ping};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const CONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to write php-fpm config: {0}")]
+    Config(#[from] std::io::Error),
+    #[error("{program} did not accept connections on {addr} within {timeout:?}")]
+    Timeout {
+        program: String,
+        addr: SocketAddr,
+        timeout: Duration,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Command starting the FastCGI server, e.g. `["php-fpm", "--nodaemonize"]`, disabled when empty
+    pub command: Vec<String>,
+
+    /// Write a php-fpm config listening on `fastcgi.bind` and pass it with `--fpm-config`
+    pub generate_fpm_config: bool,
+
+    /// Seconds to wait for the child to accept connections before giving up
+    pub start_timeout: u64,
+
+    /// Signal asking the child to stop, php-fpm finishes running requests on `SIGQUIT`
+    pub stop_signal: String,
+
+    /// Seconds to wait for the child to exit after the stop signal before killing it
+    pub stop_timeout: u64,
+
+    /// Upper bound in seconds for the delay between restarts
+    pub max_backoff: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            command: Vec::new(),
+            generate_fpm_config: false,
+            start_timeout: 30,
+            stop_signal: "SIGQUIT".into(),
+            stop_timeout: 10,
+            max_backoff: 30,
+        }
+    }
+}
+
+impl Options {
+    pub fn stop_signal(&self) -> Result<Signal, String> {
+        Signal::from_str(&self.stop_signal)
+            .map_err(|_| format!("unknown signal `{}`", self.stop_signal))
+    }
+}
+
+/// Minimal php-fpm config with one static worker per pooled connection.
+fn fpm_config(fastcgi: &manager::Options) -> String {
+    let mut config = format!(
+        "[global]
+daemonize = no
+error_log = /proc/self/fd/2
+log_limit = 8192
+
+[www]
+listen = {}
+pm = static
+pm.max_children = {}
+clear_env = no
+catch_workers_output = yes
+decorate_workers_output = no
+",
+        fastcgi.bind, fastcgi.max_conn
+    );
+
+    if let Some(path) = &fastcgi.ping_path {
+        let response = match &fastcgi.ping_body {
+            ping::Body::Exact(body) => body.as_str(),
+            _ => "pong",
+        };
+        config.push_str(&format!("ping.path = {path}\nping.response = {response}\n"));
+    }
+
+    config
+}
+
+/// Writes `config` to a fresh directory only the current user can access, so other
+/// users can neither read it nor plant a file or symlink at its path.
+fn write_fpm_config(config: &str) -> std::io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("pyper-{}", Uuid::now_v7().simple()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let path = dir.join("php-fpm.conf");
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(config.as_bytes())?;
+
+    Ok(path)
+}
+
+fn remove_fpm_config(path: &Path) {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = std::fs::remove_dir_all(dir) {
+            tracing::warn!({ ?dir, error = ?e }, "failed to remove php-fpm config");
+        }
+    }
+}
+
+/// Runs the FastCGI server as a child process and restarts it when it exits.
+pub struct Supervisor {
+    options: Options,
+    program: String,
+    args: Vec<String>,
+    addr: SocketAddr,
+    /// Generated php-fpm config, removed once the child is stopped
+    fpm_config: Option<PathBuf>,
+}
+
+impl Supervisor {
+    /// Returns `None` when no command is configured.
+    pub fn new(options: Options, fastcgi: &manager::Options) -> Result<Option<Self>, Error> {
+        let Some((program, args)) = options.command.split_first() else {
+            return Ok(None);
+        };
+
+        let mut args = args.to_vec();
+        let mut generated = None;
+
+        if options.generate_fpm_config {
+            let path = write_fpm_config(&fpm_config(fastcgi))?;
+
+            args.push("--fpm-config".into());
+            args.push(path.display().to_string());
+            generated = Some(path);
+        }
+
+        Ok(Some(Self {
+            program: program.clone(),
+            args,
+            addr: fastcgi.bind,
+            options,
+            fpm_config: generated,
+        }))
+    }
+
+    pub fn start(self) -> Handle {
+        let token = CancellationToken::new();
+        let program = self.program.clone();
+        let addr = self.addr;
+        let start_timeout = Duration::from_secs(self.options.start_timeout);
+        let fpm_config = self.fpm_config.clone();
+        let task = tokio::spawn(self.run(token.clone()));
+
+        Handle {
+            token,
+            task,
+            program,
+            addr,
+            start_timeout,
+            fpm_config,
+        }
+    }
+
+    fn spawn(&self) -> std::io::Result<Child> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            // Keep terminal signals away from the child, pyper stops it after draining
+            .process_group(0)
+            .spawn()?;
+
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(forward(self.program.clone(), "stdout", stdout));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward(self.program.clone(), "stderr", stderr));
+        }
+
+        tracing::info!({ program = self.program, pid = child.id() }, "started child process");
+
+        Ok(child)
+    }
+
+    async fn run(self, token: CancellationToken) {
+        let max_backoff = Duration::from_secs(self.options.max_backoff).max(MIN_BACKOFF);
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            let started = Instant::now();
+
+            match self.spawn() {
+                Ok(mut child) => match self.watch(&mut child, &token).await {
+                    Some(Ok(status)) => {
+                        tracing::error!({ program = self.program, %status }, "child process exited")
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!({ error = ?e }, "failed to wait for child process")
+                    }
+                    None => return self.stop(child).await,
+                },
+                Err(e) => {
+                    tracing::error!({ program = self.program, error = ?e }, "failed to start child process")
+                }
+            }
+
+            if started.elapsed() > max_backoff {
+                backoff = MIN_BACKOFF;
+            }
+
+            tracing::info!({ ?backoff }, "restarting child process");
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = token.cancelled() => return,
+            }
+
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    /// Forwards signals meant for the child until it exits or `token` is cancelled.
+    async fn watch(
+        &self,
+        child: &mut Child,
+        token: &CancellationToken,
+    ) -> Option<std::io::Result<ExitStatus>> {
+        let mut usr1 =
+            signal(SignalKind::user_defined1()).expect("failed to install signal handler");
+        let mut usr2 =
+            signal(SignalKind::user_defined2()).expect("failed to install signal handler");
+
+        loop {
+            let forwarded = tokio::select! {
+                status = child.wait() => return Some(status),
+                _ = token.cancelled() => return None,
+                _ = usr1.recv() => Signal::SIGUSR1,
+                _ = usr2.recv() => Signal::SIGUSR2,
+            };
+
+            self.kill(child, forwarded);
+        }
+    }
+
+    fn kill(&self, child: &Child, signal: Signal) {
+        let Some(pid) = child.id() else {
+            return;
+        };
+
+        tracing::info!({ pid, %signal }, "signalling child process");
+
+        if let Err(e) = nix::sys::signal::kill(Pid::from_raw(pid as i32), signal) {
+            tracing::warn!({ pid, %signal, error = %e }, "failed to signal child process");
+        }
+    }
+
+    async fn stop(&self, mut child: Child) {
+        let timeout = Duration::from_secs(self.options.stop_timeout);
+
+        self.kill(
+            &child,
+            self.options.stop_signal().unwrap_or(Signal::SIGTERM),
+        );
+
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(Ok(status)) => tracing::info!({ %status }, "child process stopped"),
+            Ok(Err(e)) => tracing::error!({ error = ?e }, "failed to wait for child process"),
+            Err(_) => {
+                tracing::warn!({ ?timeout }, "child process did not stop, killing it");
+                let _ = child.kill().await;
+            }
+        }
+    }
+}
+
+async fn forward(program: String, stream: &'static str, output: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(output).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        tracing::info!({ program, stream, line }, "child output");
+    }
+}
+
+pub struct Handle {
+    token: CancellationToken,
+    task: JoinHandle<()>,
+    program: String,
+    addr: SocketAddr,
+    start_timeout: Duration,
+    fpm_config: Option<PathBuf>,
+}
+
+impl Handle {
+    /// Waits until the child accepts connections on the FastCGI address.
+    pub async fn wait_ready(&self) -> Result<(), Error> {
+        let timeout = self.start_timeout;
+        let connect = async {
+            while TcpStream::connect(self.addr).await.is_err() {
+                tokio::time::sleep(CONNECT_INTERVAL).await;
+            }
+        };
+
+        tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| Error::Timeout {
+                program: self.program.clone(),
+                addr: self.addr,
+                timeout,
+            })
+    }
+
+    /// Sends the stop signal and waits for the child to exit.
+    pub async fn stop(self) {
+        self.token.cancel();
+
+        if let Err(e) = self.task.await {
+            tracing::error!({ error = ?e }, "supervisor task failed");
+        }
+
+        if let Some(path) = &self.fpm_config {
+            remove_fpm_config(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn writes_private_fpm_config() {
+        let first = write_fpm_config("[global]\n").unwrap();
+        let second = write_fpm_config("[global]\n").unwrap();
+        assert_ne!(first.parent(), second.parent());
+
+        let dir = first.parent().unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(dir), 0o700);
+        assert_eq!(mode(&first), 0o600);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "[global]\n");
+
+        remove_fpm_config(&first);
+        remove_fpm_config(&second);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn fpm_config_listens_on_bind() {
+        let fastcgi = manager::Options {
+            ping_path: Some("/ping".into()),
+            ..manager::Options::default()
+        };
+        let config = fpm_config(&fastcgi);
+
+        assert!(config.contains(&format!("listen = {}\n", fastcgi.bind)));
+        assert!(config.contains(&format!("pm.max_children = {}\n", fastcgi.max_conn)));
+        assert!(config.contains("ping.path = /ping\n"));
+    }
+}
@@ -0,0 +1,245 @@
+use http::HeaderMap;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
+use opentelemetry::{
+    propagation::{Extractor, Injector, TextMapPropagator},
+    trace::TracerProvider,
+    Context,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use serde::{Deserialize, Serialize};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::request::ExtraParams;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exporter {
+    /// OTLP over HTTP with protobuf
+    Otlp,
+    /// Spans printed to stdout, for local debugging
+    Stdout,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Where spans are exported to, tracing is disabled when unset
+    pub exporter: Option<Exporter>,
+
+    /// OTLP traces endpoint
+    pub endpoint: String,
+
+    pub service_name: String,
+
+    /// Share of new traces recorded, incoming sampling decisions are kept
+    pub sample_ratio: f64,
+
+    /// FastCGI param PHP reads the `traceparent` of the request span from
+    pub traceparent_param: String,
+
+    /// FastCGI param PHP reads the `tracestate` of the request span from
+    pub tracestate_param: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            exporter: None,
+            endpoint: "http://localhost:4318/v1/traces".into(),
+            service_name: "pyper".into(),
+            sample_ratio: 1.0,
+            traceparent_param: "OTEL_TRACEPARENT".into(),
+            tracestate_param: "OTEL_TRACESTATE".into(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to build span exporter: {0}")]
+pub struct Error(#[from] opentelemetry_otlp::ExporterBuildError);
+
+/// Builds the tracer provider, returns `None` when tracing is disabled.
+pub fn provider(options: &Options) -> Result<Option<SdkTracerProvider>, Error> {
+    let Some(exporter) = options.exporter else {
+        return Ok(None);
+    };
+
+    let builder = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            options.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(options.service_name.clone())
+                .build(),
+        );
+
+    let builder = match exporter {
+        Exporter::Otlp => builder.with_batch_exporter(
+            SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&options.endpoint)
+                .build()?,
+        ),
+        Exporter::Stdout => {
+            builder.with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+        }
+    };
+
+    Ok(Some(builder.build()))
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> SdkTracer {
+    provider.tracer("pyper")
+}
+
+/// Keeps `spans` open until the body was sent or dropped.
+pub fn hold<E: 'static>(body: BoxBody<Bytes, E>, spans: Vec<tracing::Span>) -> BoxBody<Bytes, E> {
+    body.map_frame(move |frame| {
+        let _ = &spans;
+        frame
+    })
+    .boxed()
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Continues the trace of incoming `traceparent` and `tracestate` headers in `span`.
+pub fn continue_trace(span: &tracing::Span, headers: &HeaderMap) {
+    let parent = TraceContextPropagator::new().extract(&Headers(headers));
+
+    // Fails only when the span is disabled
+    let _ = span.set_parent(parent);
+}
+
+struct Fields<'a> {
+    options: &'a Options,
+    params: &'a mut ExtraParams,
+}
+
+impl Injector for Fields<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        let name = match key {
+            "traceparent" => &self.options.traceparent_param,
+            "tracestate" => &self.options.tracestate_param,
+            _ => return,
+        };
+
+        if value.is_empty() {
+            return;
+        }
+
+        self.params.insert(name.clone(), value);
+    }
+}
+
+/// Adds the trace context of `span` to the params sent to PHP.
+pub fn inject(options: &Options, span: &tracing::Span, params: &mut ExtraParams) {
+    let context: Context = span.context();
+
+    TraceContextPropagator::new().inject_context(&context, &mut Fields { options, params });
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanId, SpanKind, TraceId};
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    const PARENT_ID: &str = "b7ad6b7169203331";
+
+    /// Traces a request with `headers`, returns the params for PHP and the exported spans.
+    fn traced(headers: HeaderMap) -> (ExtraParams, Vec<opentelemetry_sdk::trace::SpanData>) {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer(&provider)));
+        let mut params = ExtraParams::default();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "request",
+                otel.name = "GET /index.php",
+                otel.kind = "server",
+            );
+            continue_trace(&span, &headers);
+            inject(&Options::default(), &span, &mut params);
+        });
+
+        provider.force_flush().unwrap();
+        (params, exporter.get_finished_spans().unwrap())
+    }
+
+    #[test]
+    fn continues_incoming_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            format!("00-{TRACE_ID}-{PARENT_ID}-01").parse().unwrap(),
+        );
+        headers.insert("tracestate", "vendor=value".parse().unwrap());
+
+        let (params, spans) = traced(headers);
+        let [span] = spans.as_slice() else {
+            panic!("expected one span, got {spans:?}");
+        };
+
+        assert_eq!(span.name, "GET /index.php");
+        assert_eq!(span.span_kind, SpanKind::Server);
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+
+        // PHP continues the trace as a child of the request span
+        assert_eq!(
+            params.get("OTEL_TRACEPARENT"),
+            Some(format!("00-{TRACE_ID}-{}-01", span.span_context.span_id()).as_str())
+        );
+        assert_eq!(params.get("OTEL_TRACESTATE"), Some("vendor=value"));
+    }
+
+    #[test]
+    fn starts_new_trace() {
+        let (params, spans) = traced(HeaderMap::new());
+        let [span] = spans.as_slice() else {
+            panic!("expected one span, got {spans:?}");
+        };
+
+        assert_eq!(span.parent_span_id, SpanId::INVALID);
+        assert_eq!(
+            params.get("OTEL_TRACEPARENT"),
+            Some(
+                format!(
+                    "00-{}-{}-01",
+                    span.span_context.trace_id(),
+                    span.span_context.span_id()
+                )
+                .as_str()
+            )
+        );
+        assert_eq!(params.get("OTEL_TRACESTATE"), None);
+    }
+}
@@ -0,0 +1,540 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bb8::{ErrorSink, Pool, PooledConnection, RunError, Statistics};
+use http::request::Parts;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    fpm_status::{self, Status},
+    manager::{self, Manager, Retire},
+    metrics::METRICS,
+    ping::Ping,
+};
+
+/// Points per backend on the consistent hashing ring.
+const VIRTUAL_NODES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash,
+}
+
+/// What consistent hashing keys on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    Path,
+    Header(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// FastCGI servers to balance over, defaults to `fastcgi.bind`
+    pub backends: Vec<SocketAddr>,
+
+    pub strategy: Strategy,
+
+    /// Request property used by the `consistent_hash` strategy
+    pub hash_key: HashKey,
+
+    /// Consecutive errors after which a backend is ejected
+    pub max_failures: u32,
+
+    /// Seconds before an ejected backend is pinged for re-admission
+    pub cooldown: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backends: Vec::new(),
+            strategy: Strategy::RoundRobin,
+            hash_key: HashKey::ClientIp,
+            max_failures: 5,
+            cooldown: 10,
+        }
+    }
+}
+
+/// A FastCGI server with its own connection pool and health state.
+pub struct Backend {
+    /// Name of the upstream the backend belongs to
+    upstream: String,
+    addr: SocketAddr,
+    manager: Manager,
+    min_idle: u32,
+    /// Replaced to resize or recycle the pool, requests return connections to the
+    /// pool they got them from
+    pool: ArcSwap<Pool<Manager>>,
+    max_size: AtomicU32,
+    /// Statistics of the pools replaced by `resize`
+    retired: Mutex<Statistics>,
+    waiting: AtomicUsize,
+    /// Wakes waiting checkouts when a connect failed, instead of letting them time out
+    connect_failed: Notify,
+    fpm_status: ArcSwapOption<Status>,
+    outstanding: AtomicUsize,
+    failures: AtomicU32,
+    ejected: AtomicBool,
+    max_failures: u32,
+    cooldown: Duration,
+}
+
+impl Backend {
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn pool(&self) -> Arc<Pool<Manager>> {
+        self.pool.load_full()
+    }
+
+    pub fn manager(&self) -> &Manager {
+        &self.manager
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.max_size.load(Ordering::Relaxed)
+    }
+
+    /// Pool statistics since the backend was created, across pool replacements.
+    pub fn statistics(&self) -> Statistics {
+        add(
+            &self.retired.lock().unwrap(),
+            &self.pool().state().statistics,
+        )
+    }
+
+    /// Last values read from the php-fpm status page.
+    pub fn fpm_status(&self) -> Option<Arc<Status>> {
+        self.fpm_status.load_full()
+    }
+
+    pub fn set_fpm_status(&self, status: Option<Arc<Status>>) {
+        self.fpm_status.store(status);
+    }
+
+    /// Requests waiting for a connection.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub async fn get(
+        &self,
+    ) -> Result<PooledConnection<'static, Manager>, RunError<manager::Error>> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+
+        let pool = self.pool();
+        let connect_failed = self.connect_failed.notified();
+
+        tokio::select! {
+            conn = pool.get_owned() => conn,
+            _ = connect_failed => Err(RunError::User(manager::Error::IO(std::io::Error::other(
+                "failed to connect to backend",
+            )))),
+        }
+    }
+
+    /// Replaces the pool with one of `max_size` connections. Idle connections are
+    /// closed, busy ones once their request finished.
+    pub fn resize(self: &Arc<Self>, max_size: u32) {
+        let pool = build_pool(
+            Arc::downgrade(self),
+            self.manager.clone(),
+            max_size,
+            self.min_idle,
+        );
+
+        // Keep the counters of the old pool so the exported totals never go backwards
+        let old = self.pool.swap(Arc::new(pool));
+        let mut retired = self.retired.lock().unwrap();
+        *retired = add(&retired, &old.state().statistics);
+        drop(retired);
+
+        self.max_size.store(max_size, Ordering::Relaxed);
+        tracing::info!({ backend = %self.addr, max_size }, "replaced connection pool");
+    }
+
+    /// Replaces every connection, e.g. after php-fpm reloaded its workers.
+    pub fn recycle(self: &Arc<Self>) {
+        self.resize(self.max_size());
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.ejected.load(Ordering::Relaxed)
+    }
+
+    /// Counts the backend as busy until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> Outstanding {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        Outstanding(Arc::clone(self))
+    }
+
+    pub fn success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Records an error and ejects the backend after `max_failures` in a row.
+    pub fn failure(self: &Arc<Self>) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if failures < self.max_failures || self.ejected.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        tracing::warn!({ backend = %self.addr, failures }, "ejecting backend");
+        METRICS
+            .ejections
+            .with_label_values(&[&self.upstream, &self.addr.to_string()])
+            .inc();
+
+        tokio::spawn(readmit(Arc::downgrade(self)));
+    }
+
+    /// Opens a connection outside the pool, so a full pool doesn't hold the check up.
+    async fn check(&self) -> Result<(), String> {
+        let mut conn = self
+            .pool()
+            .dedicated_connection()
+            .await
+            .map_err(|e| e.to_string())?;
+        conn.ping().await.map_err(|e| e.to_string())
+    }
+}
+
+/// Pings an ejected backend after every cooldown until it answers, stops once
+/// the backend was replaced by a reload.
+async fn readmit(backend: Weak<Backend>) {
+    loop {
+        let Some(cooldown) = backend.upgrade().map(|b| b.cooldown) else {
+            return;
+        };
+
+        tokio::time::sleep(cooldown).await;
+
+        let Some(backend) = backend.upgrade() else {
+            return;
+        };
+
+        match backend.check().await {
+            Ok(()) => {
+                tracing::info!({ backend = %backend.addr }, "re-admitting backend");
+                backend.failures.store(0, Ordering::Relaxed);
+                backend.ejected.store(false, Ordering::Relaxed);
+                return;
+            }
+            Err(reason) => {
+                tracing::warn!({ backend = %backend.addr, reason }, "backend still failing")
+            }
+        }
+    }
+}
+
+fn add(a: &Statistics, b: &Statistics) -> Statistics {
+    let mut total = Statistics::default();
+
+    total.get_direct = a.get_direct + b.get_direct;
+    total.get_waited = a.get_waited + b.get_waited;
+    total.get_timed_out = a.get_timed_out + b.get_timed_out;
+    total.get_wait_time = a.get_wait_time + b.get_wait_time;
+    total.connections_created = a.connections_created + b.connections_created;
+    total.connections_closed_broken = a.connections_closed_broken + b.connections_closed_broken;
+    total.connections_closed_invalid = a.connections_closed_invalid + b.connections_closed_invalid;
+    total.connections_closed_max_lifetime =
+        a.connections_closed_max_lifetime + b.connections_closed_max_lifetime;
+    total.connections_closed_idle_timeout =
+        a.connections_closed_idle_timeout + b.connections_closed_idle_timeout;
+    total
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts failed connects against the backend. bb8 never returns them from `get`,
+/// a checkout just waits until it times out.
+#[derive(Clone)]
+struct ConnectErrors(Weak<Backend>);
+
+impl std::fmt::Debug for ConnectErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectErrors")
+    }
+}
+
+impl ErrorSink<manager::Error> for ConnectErrors {
+    fn sink(&self, error: manager::Error) {
+        // Failed pings and retired connections are handled by the pool itself
+        let manager::Error::IO(e) = error else {
+            return;
+        };
+        let Some(backend) = self.0.upgrade() else {
+            return;
+        };
+
+        tracing::warn!({ backend = %backend.addr, error = %e }, "failed to connect to backend");
+        backend.failure();
+        backend.connect_failed.notify_waiters();
+    }
+
+    fn boxed_clone(&self) -> Box<dyn ErrorSink<manager::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+/// Builds a pool opening its `min_idle` connections in the background, so a
+/// php-fpm that is still starting does not fail the startup or a reload. Failed
+/// connects are not retried but reported to `backend`.
+fn build_pool(
+    backend: Weak<Backend>,
+    manager: Manager,
+    max_size: u32,
+    min_idle: u32,
+) -> Pool<Manager> {
+    let retire = manager.retire();
+
+    bb8::Builder::new()
+        .max_size(max_size)
+        .retry_connection(false)
+        .error_sink(Box::new(ConnectErrors(backend)))
+        .min_idle((min_idle > 0).then_some(min_idle.min(max_size)))
+        .max_lifetime(retire.max_lifetime)
+        .idle_timeout(retire.max_idle)
+        .build_unchecked(manager)
+}
+
+pub struct Outstanding(Arc<Backend>);
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Group of backends requests are balanced over.
+pub struct Upstream {
+    fastcgi: manager::Options,
+    options: Options,
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    hash_key: HashKey,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Upstream {
+    /// `name` labels the metrics of the backends, `listen` is the address pyper
+    /// serves on.
+    pub fn new(
+        name: &str,
+        listen: SocketAddr,
+        fastcgi: &manager::Options,
+        options: &Options,
+    ) -> Self {
+        let addrs = match options.backends.is_empty() {
+            true => vec![fastcgi.bind],
+            false => options.backends.clone(),
+        };
+        let mut backends = Vec::with_capacity(addrs.len());
+
+        for addr in addrs {
+            let mut manager = Manager::new(addr)
+                .with_connect_timeout(Duration::from_millis(fastcgi.connect_timeout_ms))
+                .with_server(listen)
+                .with_retire(Retire::new(fastcgi));
+
+            if let Some(ping) = Ping::new(fastcgi) {
+                manager = manager.with_ping(ping);
+            }
+
+            let backend = Arc::new_cyclic(|backend| Backend {
+                upstream: name.to_string(),
+                addr,
+                manager: manager.clone(),
+                min_idle: fastcgi.min_idle,
+                pool: ArcSwap::from_pointee(build_pool(
+                    backend.clone(),
+                    manager,
+                    fastcgi.max_conn,
+                    fastcgi.min_idle,
+                )),
+                max_size: AtomicU32::new(fastcgi.max_conn),
+                retired: Mutex::new(Statistics::default()),
+                waiting: AtomicUsize::new(0),
+                connect_failed: Notify::new(),
+                fpm_status: ArcSwapOption::empty(),
+                outstanding: AtomicUsize::new(0),
+                failures: AtomicU32::new(0),
+                ejected: AtomicBool::new(false),
+                max_failures: options.max_failures.max(1),
+                cooldown: Duration::from_secs(options.cooldown),
+            });
+
+            if let Some(path) = fastcgi.status_path.clone() {
+                let interval = Duration::from_secs(fastcgi.status_interval.max(1));
+                tokio::spawn(fpm_status::watch(Arc::downgrade(&backend), path, interval));
+            }
+
+            backends.push(backend);
+        }
+
+        let mut ring = Vec::new();
+
+        if options.strategy == Strategy::ConsistentHash {
+            for (index, backend) in backends.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    ring.push((hash((backend.addr, node)), index));
+                }
+            }
+
+            ring.sort_unstable();
+        }
+
+        Self {
+            fastcgi: fastcgi.clone(),
+            options: options.clone(),
+            backends,
+            strategy: options.strategy,
+            hash_key: options.hash_key.clone(),
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    /// Whether the upstream was built from these settings and can be reused.
+    pub fn matches(&self, fastcgi: &manager::Options, options: &Options) -> bool {
+        self.fastcgi == *fastcgi && self.options == *options
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Picks a backend for a request, skipping ejected backends unless all are ejected.
+    pub fn pick(&self, parts: &Parts) -> Arc<Backend> {
+        let all = self.backends.iter().all(|b| b.is_ejected());
+        let admitted = |index: &usize| all || !self.backends[*index].is_ejected();
+
+        // Rotate the starting point so ties do not always favour the first backend
+        let len = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut rotated = (0..len)
+            .map(|offset| (start + offset) % len)
+            .filter(admitted);
+
+        let index = match self.strategy {
+            Strategy::RoundRobin => rotated.next(),
+            Strategy::LeastOutstanding => {
+                rotated.min_by_key(|index| self.backends[*index].outstanding())
+            }
+            Strategy::ConsistentHash => {
+                let key = self.key(parts);
+                let start = self.ring.partition_point(|(point, _)| *point < key);
+
+                self.ring
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(self.ring.len())
+                    .map(|(_, index)| *index)
+                    .find(admitted)
+            }
+        };
+
+        Arc::clone(&self.backends[index.unwrap_or_default()])
+    }
+
+    fn key(&self, parts: &Parts) -> u64 {
+        match &self.hash_key {
+            HashKey::ClientIp => hash(parts.extensions.get::<SocketAddr>().map(|a| a.ip())),
+            HashKey::Path => hash(parts.uri.path()),
+            HashKey::Header(name) => hash(parts.headers.get(name).map(|v| v.as_bytes())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ejects_backend_refusing_connections() {
+        // Bound and released again, so nothing listens on it
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &manager::Options::default(),
+            &Options {
+                backends: vec![closed],
+                max_failures: 2,
+                cooldown: 60,
+                ..Options::default()
+            },
+        );
+        let backend = &upstream.backends()[0];
+
+        for _ in 0..2 {
+            let checkout = tokio::time::timeout(Duration::from_secs(1), backend.get()).await;
+            assert!(matches!(checkout, Ok(Err(RunError::User(_)))));
+        }
+
+        assert!(backend.is_ejected());
+    }
+
+    #[tokio::test]
+    async fn keeps_statistics_across_resizes() {
+        let upstream = Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &manager::Options::default(),
+            &Options::default(),
+        );
+        let backend = &upstream.backends()[0];
+        let mut retired = Statistics::default();
+        retired.connections_created = 3;
+        *backend.retired.lock().unwrap() = retired;
+
+        backend.resize(2);
+
+        assert_eq!(backend.max_size(), 2);
+        assert_eq!(backend.statistics().connections_created, 3);
+    }
+}
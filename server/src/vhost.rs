@@ -0,0 +1,272 @@
+use std::{cmp::Reverse, collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use http::{header::HOST, HeaderMap, Request, StatusCode};
+use hyper_staticfile::Static;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{FileCache, Opener},
+    headers, manager,
+    paths::Paths,
+    upstream,
+    upstream::Upstream,
+};
+
+/// How requests for hosts missing from the table are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unknown {
+    /// Serve them from the top-level `root_dir` and upstream
+    Default,
+    /// 421 Misdirected Request
+    Misdirected,
+    /// 404 Not Found
+    NotFound,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// Host names like `example.com`, or `*.example.com` for any subdomain
+    pub names: Vec<String>,
+
+    pub root_dir: PathBuf,
+
+    /// Script handling requests for missing files, relative to `root_dir`
+    #[serde(default = "default_front_controller")]
+    pub front_controller: PathBuf,
+
+    /// Paths always served from disk, never by the front controller
+    #[serde(default)]
+    pub static_paths: Paths,
+
+    /// FastCGI settings replacing the top-level `fastcgi` section for this host
+    #[serde(default)]
+    pub fastcgi: Option<manager::Options>,
+
+    /// Upstream settings replacing the top-level `upstream` section for this host
+    #[serde(default)]
+    pub upstream: Option<upstream::Options>,
+
+    /// Headers added to every response
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_front_controller() -> PathBuf {
+    PathBuf::from("index.php")
+}
+
+impl Host {
+    pub fn headers(&self) -> Result<HeaderMap, String> {
+        headers::parse(&self.headers)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    pub unknown: Unknown,
+    pub hosts: Vec<Host>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            unknown: Unknown::Default,
+            hosts: Vec::new(),
+        }
+    }
+}
+
+/// Document root, routing and upstream serving a host.
+pub struct Site {
+    pub root: PathBuf,
+    pub files: Static<Opener>,
+    pub upstream: Arc<Upstream>,
+    pub front_controller: PathBuf,
+    pub static_paths: Paths,
+    pub headers: HeaderMap,
+}
+
+impl Site {
+    pub fn new(root: PathBuf, upstream: Arc<Upstream>) -> Self {
+        Self {
+            files: Static::with_opener(Opener::new(root.clone(), None)),
+            root,
+            upstream,
+            front_controller: default_front_controller(),
+            static_paths: Paths::default(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn with_routing(mut self, front_controller: PathBuf, static_paths: Paths) -> Self {
+        self.front_controller = front_controller;
+        self.static_paths = static_paths;
+        self
+    }
+
+    pub fn with_file_cache(mut self, cache: Arc<FileCache>) -> Self {
+        self.files = Static::with_opener(Opener::new(self.root.clone(), Some(cache)));
+        self
+    }
+
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+}
+
+/// Maps host names to sites, exact names win over wildcards and longer wildcards
+/// over shorter ones.
+pub struct Hosts {
+    exact: BTreeMap<String, Arc<Site>>,
+    /// Suffixes like `.example.com`, longest first
+    wildcards: Vec<(String, Arc<Site>)>,
+    /// Only set when unknown hosts are served from the top-level site
+    default: Option<Arc<Site>>,
+    unknown: Unknown,
+}
+
+impl Hosts {
+    pub fn new(unknown: Unknown) -> Self {
+        Self {
+            exact: BTreeMap::new(),
+            wildcards: Vec::new(),
+            default: None,
+            unknown,
+        }
+    }
+
+    pub fn with_default(mut self, site: Arc<Site>) -> Self {
+        self.default = Some(site);
+        self
+    }
+
+    pub fn add(&mut self, name: &str, site: Arc<Site>) {
+        let name = name.to_ascii_lowercase();
+
+        match name.strip_prefix('*') {
+            Some(suffix) => {
+                self.wildcards.push((suffix.to_string(), site));
+                self.wildcards
+                    .sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+            }
+            None => {
+                self.exact.insert(name, site);
+            }
+        }
+    }
+
+    /// Every site, starting with the default one.
+    pub fn sites(&self) -> impl Iterator<Item = &Arc<Site>> {
+        self.default
+            .iter()
+            .chain(self.exact.values())
+            .chain(self.wildcards.iter().map(|(_, site)| site))
+    }
+
+    /// Finds the site for a request, or the status to answer with for unknown hosts.
+    pub fn find<B>(&self, request: &Request<B>) -> Result<&Arc<Site>, StatusCode> {
+        let host = request.uri().host().or_else(|| {
+            let header = request.headers().get(HOST)?.to_str().ok()?;
+
+            // Strip the port, keeping bracketed IPv6 addresses intact
+            match header.rsplit_once(':') {
+                Some((host, port)) if !port.contains(']') => Some(host),
+                _ => Some(header),
+            }
+        });
+
+        if let Some(host) = host {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+            if let Some(site) = self.exact.get(&host) {
+                return Ok(site);
+            }
+
+            if let Some((_, site)) = self
+                .wildcards
+                .iter()
+                .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            {
+                return Ok(site);
+            }
+        }
+
+        match self.unknown {
+            Unknown::Default => self.default.as_ref().ok_or(StatusCode::NOT_FOUND),
+            Unknown::Misdirected => Err(StatusCode::MISDIRECTED_REQUEST),
+            Unknown::NotFound => Err(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(root: &str) -> Arc<Site> {
+        let upstream = Upstream::new(
+            "default",
+            ([127, 0, 0, 1], 3000).into(),
+            &Default::default(),
+            &Default::default(),
+        );
+        Arc::new(Site::new(PathBuf::from(root), Arc::new(upstream)))
+    }
+
+    fn hosts(unknown: Unknown) -> Hosts {
+        let mut hosts = Hosts::new(unknown);
+        hosts.add("example.com", site("/exact"));
+        hosts.add("*.example.com", site("/wildcard"));
+        hosts.add("*.api.example.com", site("/api"));
+        hosts.add("[::1]", site("/ipv6"));
+        hosts
+    }
+
+    fn root(hosts: &Hosts, host: &str) -> Result<PathBuf, StatusCode> {
+        let request = Request::get("/").header(HOST, host).body(()).unwrap();
+        hosts.find(&request).map(|site| site.root.clone())
+    }
+
+    #[tokio::test]
+    async fn prefers_exact_and_longer_names() {
+        let hosts = hosts(Unknown::NotFound);
+
+        assert_eq!(root(&hosts, "example.com"), Ok("/exact".into()));
+        assert_eq!(root(&hosts, "www.example.com"), Ok("/wildcard".into()));
+        assert_eq!(root(&hosts, "v1.api.example.com"), Ok("/api".into()));
+        assert_eq!(root(&hosts, "api.example.com"), Ok("/wildcard".into()));
+    }
+
+    #[tokio::test]
+    async fn normalizes_host_header() {
+        let hosts = hosts(Unknown::NotFound);
+
+        assert_eq!(root(&hosts, "Example.COM:8080"), Ok("/exact".into()));
+        assert_eq!(root(&hosts, "example.com."), Ok("/exact".into()));
+        assert_eq!(root(&hosts, "[::1]:8080"), Ok("/ipv6".into()));
+        assert_eq!(root(&hosts, "[::1]"), Ok("/ipv6".into()));
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_hosts() {
+        assert_eq!(
+            root(&hosts(Unknown::NotFound), "other.org"),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            root(&hosts(Unknown::Misdirected), "other.org"),
+            Err(StatusCode::MISDIRECTED_REQUEST)
+        );
+
+        let hosts = hosts(Unknown::Default).with_default(site("/default"));
+        assert_eq!(root(&hosts, "other.org"), Ok("/default".into()));
+        assert_eq!(
+            hosts.sites().next().unwrap().root,
+            PathBuf::from("/default")
+        );
+    }
+}